@@ -1,34 +1,102 @@
 use std::{
     collections::VecDeque,
+    io::{Read, Seek, SeekFrom},
     sync::{
 	Arc,
 	atomic::{AtomicUsize, Ordering},
 	mpsc::{Receiver, sync_channel, TrySendError},
     },
     fs::File,
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 use anyhow::anyhow;
-use lewton::inside_ogg::OggStreamReader;
 use log::{trace, warn};
+use ogg::PacketReader;
 
-use crate::Terminator;
+use crate::{
+    Terminator,
+    packet::Packet,
+    pipeline::{PipelineError, Workers},
+};
+
+mod adx;
+mod aiff;
+mod bytes;
+mod flac;
+mod granule;
+mod mp3;
+mod nintendo;
+mod opus;
+mod sidecar;
+mod vorbis;
+mod wav;
 
 const DESIRED_CROSSLAP_AMOUNT: usize = 32;
 
-fn crosslap_onto(o: &mut[f32], i: &[f32], channel_count: u32) {
+/// The shape of the cross-lap at the loop point.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum CrossfadeCurve {
+    /// Straight lines. Dips in the middle if the two sides aren't correlated.
+    Linear,
+    /// Keeps the total power constant, for uncorrelated material.
+    EqualPower,
+    /// Like linear, but eases in and out at the ends.
+    RaisedCosine,
+}
+
+impl std::str::FromStr for CrossfadeCurve {
+    type Err = String;
+    fn from_str(s: &str) -> Result<CrossfadeCurve, String> {
+	match s {
+	    "linear" => Ok(CrossfadeCurve::Linear),
+	    "equal-power" => Ok(CrossfadeCurve::EqualPower),
+	    "raised-cosine" => Ok(CrossfadeCurve::RaisedCosine),
+	    _ => Err(format!("{:?} should be \"linear\", \"equal-power\", \
+			      or \"raised-cosine\"", s)),
+	}
+    }
+}
+
+impl CrossfadeCurve {
+    /// Returns the gains for the side fading in and the side fading out,
+    /// `x` of the way through the cross-lap.
+    fn gains(self, x: f32) -> (f32, f32) {
+	use std::f32::consts::{FRAC_PI_2, PI};
+	match self {
+	    CrossfadeCurve::Linear => (x, 1.0 - x),
+	    CrossfadeCurve::EqualPower
+		=> ((x * FRAC_PI_2).sin(), (x * FRAC_PI_2).cos()),
+	    CrossfadeCurve::RaisedCosine => {
+		let x = 0.5 - 0.5 * (x * PI).cos();
+		(x, 1.0 - x)
+	    },
+	}
+    }
+}
+
+fn crosslap_onto(o: &mut[f32], i: &[f32], channel_count: u32,
+		 curve: CrossfadeCurve) {
     let lap_len = o.len() / channel_count as usize;
     for (n, (o, i)) in o.chunks_mut(channel_count as usize)
 	.zip(i.chunks(channel_count as usize)).enumerate() {
-	    let o_scale = (n as f32 + 0.5) / (lap_len as f32);
-	    let i_scale = 1.0 - o_scale;
+	    let (o_scale, i_scale)
+		= curve.gains((n as f32 + 0.5) / (lap_len as f32));
 	    for channel in 0 .. o.len() {
 		o[channel] = o[channel] * o_scale + i[channel] * i_scale;
 	    }
     }
 }
 
+/// How many times to play a loop of `loop_len` so that, along with the
+/// intro and outro, playback lasts as close to `duration` as it can. (Always
+/// at least once.)
+fn plays_for_duration(duration: usize, intro_and_outro: usize,
+		      loop_len: usize) -> usize {
+    (duration.saturating_sub(intro_and_outro) as f64
+     / loop_len.max(1) as f64).round().max(1.0) as usize
+}
+
 fn mix_onto(o: &mut[f32], i: &[f32]) {
     assert_eq!(o.len(), i.len());
     for (o, i) in o.iter_mut().zip(i.iter()) {
@@ -36,148 +104,535 @@ fn mix_onto(o: &mut[f32], i: &[f32]) {
     }
 }
 
-pub fn start_decoding(path: &Path, terminator: Terminator)
-		      -> anyhow::Result<(u32, u32, usize, Arc<AtomicUsize>, Receiver<(usize,Vec<f32>)>)> {
-    let file = File::open(path)?;
-    let mut osr = OggStreamReader::new(file)?;
-    let channel_count = match osr.ident_hdr.audio_channels {
-	1 | 2 => osr.ident_hdr.audio_channels as u32,
-	x => return Err(anyhow!("unhandled channel count: {}", x)),
+/// The audio between the loop points, or as much of it as we need. Usually
+/// that's all of it, but once a loop outgrows `--max-loop-memory`, we only
+/// keep its ends (for lapping) and decode the rest again every time around.
+struct LoopBuf {
+    /// the start of the loop: all of it, or just the first `keep` floats
+    head: Vec<f32>,
+    /// the last `keep` floats or so, once we've stopped keeping everything
+    tail: Option<Vec<f32>>,
+    /// how long the loop is, whether or not we're keeping all of it
+    len: usize,
+    keep: usize,
+    limit: usize,
+}
+
+impl LoopBuf {
+    fn new(limit: usize, keep: usize) -> LoopBuf {
+	LoopBuf {
+	    head: Vec::new(), tail: None, len: 0, keep,
+	    // (the ends can't overlap)
+	    limit: limit.max(keep.saturating_mul(2)),
+	}
+    }
+    fn len(&self) -> usize { self.len }
+    /// True if we're only keeping the ends of the loop.
+    fn is_streaming(&self) -> bool { self.tail.is_some() }
+    fn extend_from_slice(&mut self, floats: &[f32]) {
+	self.len += floats.len();
+	match self.tail.as_mut() {
+	    None => {
+		self.head.extend_from_slice(floats);
+		if self.head.len() > self.limit {
+		    trace!("Loop is bigger than --max-loop-memory, will decode \
+			    it again every time around");
+		    let tail = self.head[self.head.len() - self.keep ..]
+			.to_vec();
+		    self.head.truncate(self.keep);
+		    self.head.shrink_to_fit();
+		    self.tail = Some(tail);
+		}
+	    },
+	    Some(tail) => {
+		tail.extend_from_slice(floats);
+		if tail.len() > self.keep.max(floats.len()) * 2 {
+		    tail.drain(.. tail.len() - self.keep);
+		}
+	    },
+	}
+    }
+    /// Takes the last `len` floats off the end of the loop. `len` can't be
+    /// more than `keep`.
+    fn split_off(&mut self, len: usize) -> Vec<f32> {
+	self.len -= len;
+	match self.tail.as_mut() {
+	    None => self.head.split_off(self.len),
+	    Some(tail) => tail.split_off(tail.len() - len),
+	}
+    }
+    /// The start of the loop, as much of it as we have.
+    fn head_mut(&mut self) -> &mut [f32] { &mut self.head[..] }
+    /// Hands over the start of the loop, for sending over and over.
+    fn share_head(&mut self) -> Arc<Vec<f32>> {
+	Arc::new(std::mem::take(&mut self.head))
+    }
+}
+
+/// Something that produces interleaved audio for the decode thread.
+pub trait Source: Send {
+    /// Decodes the next chunk of the stream. Returns `None` at the end of the
+    /// stream. Empty chunks are fine, and are skipped.
+    fn next_packet(&mut self) -> anyhow::Result<Option<Vec<f32>>>;
+    /// Whether `seek` works on this stream.
+    fn can_seek(&self) -> bool { false }
+    /// Goes back (or forward) so that the next chunk starts exactly at the
+    /// given sample frame.
+    fn seek(&mut self, _frame: usize) -> anyhow::Result<()> {
+	Err(anyhow!("can't seek in this kind of stream"))
+    }
+    /// Lets the stream keep up to `limit` bytes of itself in memory, if that
+    /// saves it from going back to the file every time it seeks back to the
+    /// same place.
+    fn cache_loops(&mut self, _limit: usize) {}
+}
+
+/// Where the loop is, in sample frames.
+#[derive(Debug,Clone,Copy)]
+pub struct LoopPoints {
+    /// The first frame "in" the loop.
+    pub left: usize,
+    /// The first frame "not in" the loop, or `usize::MAX` to loop at the end.
+    pub right: usize,
+    /// Whether audio after the loop gets mixed back into it. (`LOOP_MIX`)
+    pub mix: bool,
+    /// How many frames to cross-lap at the loop point, if not the default.
+    /// (`LOOP_CROSSFADE`)
+    pub crossfade: Option<usize>,
+}
+
+impl LoopPoints {
+    /// Interprets the loop metadata in a set of Vorbis-style comments.
+    /// `sample_rate` is the rate at which sample-based metadata is counted.
+    pub fn from_comments<K, V>(comments: &[(K, V)], sample_rate: u32)
+			       -> anyhow::Result<LoopPoints>
+    where K: AsRef<str>, V: AsRef<str> {
+	let mut loop_start = None;
+	let mut loop_end = None;
+	let mut loopstart = None;
+	let mut looplength = None;
+	let mut loop_mix = None;
+	let mut loop_crossfade = None;
+	for (key, value) in comments.iter() {
+	    let key = key.as_ref().to_lowercase();
+	    let value = value.as_ref();
+	    trace!("{}={}", key, value);
+	    match key.as_str() {
+		"loop_start" => loop_start = Some(value),
+		"loop_end" => loop_end = Some(value),
+		"loopstart" => loopstart = Some(value),
+		"looplength" => looplength = Some(value),
+		"loop_mix" => loop_mix = Some(value),
+		"loop_crossfade" => loop_crossfade = Some(value),
+		_ => (),
+	    }
+	}
+	let loop_mix = loop_mix.is_some();
+	let loop_left = if let Some(x) = loop_start {
+            let result = match x.parse::<usize>() {
+		Ok(x) if x >= sample_rate as usize => {
+                    warn!("LOOP_START={} looks like a sample count, \
+			   should be seconds or LOOPSTART instead", x);
+                    x
+		},
+		_ => {
+                    (x.parse::<f64>()? * sample_rate as f64).ceil() as usize
+		},
+            };
+	    trace!("LOOP_START={} → {}", x, result);
+	    result
+	}
+	else if let Some(x) = loopstart {
+	    let result = x.parse::<usize>()?;
+	    trace!("LOOPSTART={} → {}", x, result);
+	    result
+	}
+	else { 0 };
+	let loop_right = if let Some(x) = loop_end {
+            let result = match x.parse::<usize>() {
+		Ok(x) if x >= sample_rate as usize => {
+                    warn!("LOOP_END={} looks like a sample count, \
+			   should be seconds or LOOPLENGTH instead", x);
+                    x
+		},
+		_ => {
+                    (x.parse::<f64>()? * sample_rate as f64).ceil() as usize
+		},
+            };
+	    trace!("LOOP_END={} → {}", x, result);
+	    result
+	}
+	else if let Some(x) = looplength {
+	    let result = loop_left.saturating_add(x.parse::<usize>()?);
+	    trace!("LOOPLENGTH={} → {}", x, result);
+	    result
+	}
+	else { usize::MAX };
+	let crossfade = match loop_crossfade {
+	    Some(x) => {
+		let result = (x.parse::<f64>()? * sample_rate as f64).ceil()
+		    as usize;
+		trace!("LOOP_CROSSFADE={} → {}", x, result);
+		Some(result)
+	    },
+	    None => None,
+	};
+	Ok(LoopPoints { left: loop_left, right: loop_right, mix: loop_mix,
+			crossfade })
+    }
+}
+
+/// A point in time, as given by something other than the file itself.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum Time {
+    Seconds(f64),
+    Samples(usize),
+}
+
+impl Time {
+    pub fn to_frames(self, sample_rate: u32) -> usize {
+	match self {
+	    Time::Seconds(x) => (x * sample_rate as f64).ceil() as usize,
+	    Time::Samples(x) => x,
+	}
+    }
+}
+
+/// Parses seconds (`12.5`), minutes and seconds (`1:02.5`), hours, minutes,
+/// and seconds (`1:00:02.5`), or a sample count (`44100s` or `44100smp`).
+impl std::str::FromStr for Time {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Time, String> {
+	let s = s.trim();
+	if let Some(x) = s.strip_suffix("smp").or_else(|| s.strip_suffix('s')) {
+	    return x.parse().map(Time::Samples)
+		.map_err(|_| format!("{:?} is not a valid sample count", s))
+	}
+	let bad = || format!("{:?} is not a valid time (expected seconds, \
+			      mm:ss, hh:mm:ss, or samples followed by \"s\")",
+			     s);
+	let mut parts = s.rsplit(':');
+	let seconds = parts.next().and_then(|x| x.parse::<f64>().ok())
+	    .filter(|x| x.is_finite() && *x >= 0.0)
+	    .ok_or_else(bad)?;
+	let mut total = seconds;
+	for (n, part) in parts.enumerate() {
+	    // minutes, then hours, and nothing bigger
+	    let x = match (n, part.parse::<u32>()) {
+		(0, Ok(x)) => x as f64 * 60.0,
+		(1, Ok(x)) => x as f64 * 3600.0,
+		_ => return Err(bad()),
+	    };
+	    total += x;
+	}
+	Ok(Time::Seconds(total))
+    }
+}
+
+/// Loop settings that override what the file itself says. Anything left as
+/// `None` is left alone.
+#[derive(Debug,Clone,Copy,Default,PartialEq)]
+pub struct LoopOverrides {
+    pub start: Option<Time>,
+    pub end: Option<Time>,
+    /// Only used if `end` isn't given.
+    pub length: Option<Time>,
+    pub mix: Option<bool>,
+    pub crossfade: Option<Time>,
+}
+
+impl LoopOverrides {
+    pub fn apply(&self, loop_points: &mut LoopPoints, sample_rate: u32) {
+	if let Some(x) = self.start {
+	    loop_points.left = x.to_frames(sample_rate);
+	    trace!("loop start overridden: {:?} → {}", x, loop_points.left);
+	}
+	if let Some(x) = self.end {
+	    loop_points.right = x.to_frames(sample_rate);
+	    trace!("loop end overridden: {:?} → {}", x, loop_points.right);
+	}
+	else if let Some(x) = self.length {
+	    loop_points.right = loop_points.left
+		.saturating_add(x.to_frames(sample_rate));
+	    trace!("loop length overridden: {:?} → {}", x, loop_points.right);
+	}
+	if let Some(x) = self.mix {
+	    loop_points.mix = x;
+	}
+	if let Some(x) = self.crossfade {
+	    loop_points.crossfade = Some(x.to_frames(sample_rate));
+	    trace!("loop crossfade overridden: {:?} → {}", x,
+		   x.to_frames(sample_rate));
+	}
+    }
+}
+
+/// An opened audio file, ready to be decoded.
+pub struct Stream {
+    pub sample_rate: u32,
+    pub channel_count: u32,
+    pub loop_points: LoopPoints,
+    /// How many sample frames are in the stream, if we can tell without
+    /// decoding it.
+    pub length: Option<usize>,
+    pub source: Box<dyn Source>,
+}
+
+/// Finds out how many sample frames are in a stream, the hard way.
+fn measure(mut source: Box<dyn Source>, channel_count: u32)
+	   -> anyhow::Result<usize> {
+    let mut length = 0;
+    while let Some(x) = source.next_packet()? {
+	length += x.len() / channel_count as usize;
+    }
+    Ok(length)
+}
+
+/// Figures out what kind of file this is, and opens it accordingly.
+fn open(path: &Path, strict: bool) -> anyhow::Result<Stream> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 4];
+    file.read_exact(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+    match &magic {
+	b"fLaC" => flac::open_native(file),
+	b"RIFF" => wav::open(file),
+	b"FORM" => aiff::open(file),
+	b"RSTM" | b"CSTM" | b"FSTM" => nintendo::open(file),
+	[0x80, 0x00, _, _] => adx::open(file),
+	[b'I', b'D', b'3', _] => mp3::open(file),
+	[0xFF, x, _, _] if x & 0xE0 == 0xE0 => mp3::open(file),
+	b"OggS" => {
+	    // peek at the first packet to see which codec is inside
+	    let mut rdr = PacketReader::new(file);
+	    let first_packet = match rdr.read_packet()? {
+		Some(x) => x,
+		None => return Err(anyhow!("file contains no Ogg packets")),
+	    };
+	    let mut file = rdr.into_inner();
+	    file.seek(SeekFrom::Start(0))?;
+	    if first_packet.data.starts_with(b"\x01vorbis") {
+		vorbis::open(file, strict)
+	    }
+	    else if first_packet.data.starts_with(b"OpusHead") {
+		opus::open(file)
+	    }
+	    else if first_packet.data.starts_with(b"\x7FFLAC") {
+		flac::open_ogg(file)
+	    }
+	    else {
+		Err(anyhow!("unknown codec inside Ogg file"))
+	    }
+	},
+	_ => Err(anyhow!("not a file format we know how to play")),
+    }
+}
+
+/// How the user wants the file played, beyond what the file itself says.
+#[derive(Debug,Default)]
+pub struct Options {
+    /// A loop file to read, instead of looking for one next to the audio.
+    pub loop_file: Option<PathBuf>,
+    /// Overrides to apply on top of the file's metadata and any loop file.
+    pub overrides: LoopOverrides,
+    /// Where to start playback, instead of the beginning.
+    pub from: Option<Time>,
+    /// Where to end playback, instead of the end.
+    pub to: Option<Time>,
+    /// How many times to play the loop before moving on, instead of forever.
+    pub loops: Option<usize>,
+    /// The shape of the cross-lap at the loop point, if not the default.
+    pub crossfade_curve: Option<CrossfadeCurve>,
+    /// How much of the end of the loop to hold back and lap onto its start
+    /// instead, for audio that was never meant to loop.
+    pub ambient_fade: Option<Time>,
+    /// Whether damage to the file should stop playback, instead of being
+    /// papered over.
+    pub strict: bool,
+    /// Roughly how long to play for, instead of forever. The number of loops
+    /// is picked so that the song's natural ending lands as close to this as
+    /// possible.
+    pub duration: Option<Time>,
+    /// How many bytes of decoded loop to keep in memory, at most. Bigger
+    /// loops are decoded again every time around, if the stream can seek.
+    pub max_loop_memory: Option<usize>,
+}
+
+pub fn start_decoding(path: &Path, options: Options, terminator: Terminator)
+		      -> anyhow::Result<(u32, u32, usize, Arc<AtomicUsize>, Option<usize>, Receiver<(usize,Packet)>, Workers)> {
+    let Stream { sample_rate, channel_count, mut loop_points, length,
+		 mut source } = open(path, options.strict)?;
+    let loop_file = match options.loop_file {
+	Some(x) => Some(x),
+	None => sidecar::find(path),
     };
-    let sample_rate = match osr.ident_hdr.audio_sample_rate {
-	0 => return Err(anyhow!("stream says it's 0Hz, that unpossible")),
-	x => x,
+    if let Some(loop_file) = loop_file {
+	trace!("Reading loop file: {}", loop_file.display());
+	sidecar::read(&loop_file)?.apply(&mut loop_points, sample_rate);
+    }
+    options.overrides.apply(&mut loop_points, sample_rate);
+    let from = options.from.map(|x| x.to_frames(sample_rate)).unwrap_or(0);
+    let to = options.to.map(|x| x.to_frames(sample_rate))
+	.unwrap_or(usize::MAX);
+    if to <= from {
+	return Err(anyhow!("playback would end before it starts"))
+    }
+    if loop_points.left < from {
+	return Err(anyhow!("loop would start before playback does"))
+    }
+    if loop_points.right > to {
+	// nothing past the end of playback can be in the loop
+	loop_points.right = to;
+    }
+    // make sure the loop is actually in the stream
+    let has_loop_points = loop_points.left > 0
+	|| loop_points.right != usize::MAX;
+    let length = match length {
+	Some(x) => Some(x),
+	None if has_loop_points => {
+	    trace!("Measuring the stream by decoding it");
+	    Some(measure(open(path, options.strict)?.source, channel_count)?)
+	},
+	None => None,
     };
-    trace!("Vendor: {}", osr.comment_hdr.vendor);
-    let mut loop_start = None;
-    let mut loop_end = None;
-    let mut loopstart = None;
-    let mut looplength = None;
-    let mut loop_mix = None;
-    for (key, value) in osr.comment_hdr.comment_list.iter() {
-	let key = key.to_lowercase();
-	trace!("{}={}", key, value);
-	match key.as_str() {
-	    "loop_start" => loop_start = Some(value),
-	    "loop_end" => loop_end = Some(value),
-	    "loopstart" => loopstart = Some(value),
-	    "looplength" => looplength = Some(value),
-	    "loop_mix" => loop_mix = Some(value),
-	    _ => (),
+    let end = length.map(|x| x.min(to));
+    if let Some(length) = end {
+	let past_end = |point, at, instead: String| {
+	    let err = PipelineError::LoopPastEnd { point, at, length };
+	    if options.strict { return Err(err) }
+	    warn!("{}, {} instead", err, instead);
+	    Ok(())
+	};
+	if loop_points.left >= length {
+	    past_end("LOOP_START", loop_points.left,
+		     format!("looping from sample {} to the end", from))?;
+	    loop_points.left = from;
+	    loop_points.right = usize::MAX;
 	}
+	else if loop_points.right != usize::MAX && loop_points.right > length {
+	    past_end("LOOP_END", loop_points.right,
+		     format!("ending the loop at sample {}", length))?;
+	    loop_points.right = length;
+	}
+    }
+    if loop_points.right <= loop_points.left {
+	return Err(PipelineError::LoopEndBeforeStart {
+	    start: loop_points.left,
+	    end: loop_points.right,
+	}.into())
     }
-    let loop_mix = loop_mix.is_some();
-    let loop_left = if let Some(x) = loop_start {
-        let result = match x.parse::<usize>() {
-            Ok(x) if x >= sample_rate as usize => {
-                warn!("LOOP_START={} looks like a sample count, \
-                       should be seconds or LOOPSTART instead", x);
-                x
-            },
-            _ => {
-                (x.parse::<f64>()? * sample_rate as f64).ceil() as usize
-            },
-        };
-	trace!("LOOP_START={} → {}", x, result);
-	result
-    }
-    else if let Some(x) = loopstart {
-	let result = x.parse::<usize>()?;
-	trace!("LOOPSTART={} → {}", x, result);
-	result
-    }
-    else { 0 };
-    let loop_right = if let Some(x) = loop_end {
-        let result = match x.parse::<usize>() {
-            Ok(x) if x >= sample_rate as usize => {
-                warn!("LOOP_END={} looks like a sample count, \
-                       should be seconds or LOOPLENGTH instead", x);
-                x
-            },
-            _ => {
-                (x.parse::<f64>()? * sample_rate as f64).ceil() as usize
-            },
-        };
-	trace!("LOOP_END={} → {}", x, result);
-	result
-    }
-    else if let Some(x) = looplength {
-	let result = loop_left.saturating_add(x.parse::<usize>()?);
-	trace!("LOOPLENGTH={} → {}", x, result);
-	result
-    }
-    else { usize::MAX };
+    let LoopPoints { left: loop_left, right: loop_right, mix: loop_mix,
+		     crossfade } = loop_points;
+    let mut crossfade_i = crossfade.unwrap_or(DESIRED_CROSSLAP_AMOUNT)
+	.saturating_mul(channel_count as usize);
+    let mut crossfade_curve = options.crossfade_curve
+	.unwrap_or(CrossfadeCurve::Linear);
+    let holdback = match options.ambient_fade {
+	Some(_) if loop_mix => {
+	    warn!("can't do an ambient fade and LOOP_MIX at the same time");
+	    0
+	},
+	Some(x) => x.to_frames(sample_rate)
+	    .saturating_mul(channel_count as usize),
+	None => 0,
+    };
+    // ambient fades are usually between unrelated sounds
+    let ambient_curve = options.crossfade_curve
+	.unwrap_or(CrossfadeCurve::EqualPower);
     let loop_left_i: usize = loop_left.saturating_mul(channel_count as usize);
     let loop_right_i: usize =loop_right.saturating_mul(channel_count as usize);
+    let from_i = from.saturating_mul(channel_count as usize);
+    let to_i = to.saturating_mul(channel_count as usize);
+    let end_i = end.map(|x| x.saturating_mul(channel_count as usize));
     let loop_right_atom = Arc::new(AtomicUsize::new(
-	if loop_right_i == usize::MAX { 0 }
+	// if the loop runs to the end and we know where that is, the loop
+	// thread will just confirm it
+	if loop_right_i == usize::MAX { end_i.unwrap_or(0) }
 	else { loop_right_i }
     ));
     let loop_right_atom_clone = loop_right_atom.clone();
+    let max_plays = options.loops;
+    let time_unit = (sample_rate as usize)
+	.saturating_mul(channel_count as usize);
+    let duration_i = options.duration.map(|x| x.to_frames(sample_rate)
+					  .saturating_mul(channel_count
+							  as usize));
+    // a loop too big to keep in memory gets decoded again, from a second
+    // copy of the stream, every time around. (LOOP_MIX needs all of it.)
+    let loop_limit = match options.max_loop_memory {
+	Some(x) if source.can_seek() && !loop_mix
+	    => x / std::mem::size_of::<f32>(),
+	_ => usize::MAX,
+    };
+    let rewinder_path = path.to_path_buf();
+    let strict = options.strict;
     let (decode_tx, decode_rx) = sync_channel(crate::NUM_PACKETS_BUFFERED);
-    let _ = std::thread::Builder::new().name("decode thread".to_string())
+    let decode_terminator = terminator.clone();
+    let decode_thread = std::thread::Builder::new()
+	.name("decode thread".to_string())
 	.spawn(move || {
-	    loop {
-		let pkt
-		    = match osr.read_dec_packet_generic::<Vec<Vec<f32>>>()
-		    .expect("error while decoding stream") {
-			Some(x) if x.len() == 0 || x[0].len() == 0 => {
-			    continue
-			},
-			Some(x) => x,
-			None => break,
-		    };
-		let buf_to_send = match channel_count {
-		    1 => {
-			assert_eq!(pkt.len(), 1);
-			pkt.into_iter().next().unwrap()
-		    },
-		    2 => {
-			assert_eq!(pkt.len(), 2);
-			assert_eq!(pkt[0].len(), pkt[1].len());
-			let mut out_buf
-			    = Vec::with_capacity(pkt[0].len()*2);
-			for (&l, &r) in pkt[0].iter().zip(pkt[1].iter()) {
-			    out_buf.push(l);
-			    out_buf.push(r);
-			}
-			out_buf
+	    // the position of the next float we decode
+	    let mut pos = 0;
+	    while pos < to_i {
+		let mut buf_to_send = match source.next_packet() {
+		    Ok(Some(x)) if x.is_empty() => continue,
+		    Ok(Some(x)) => x,
+		    Ok(None) => break,
+		    Err(x) => {
+			// nothing more is coming, stop playback
+			decode_terminator.terminate();
+			return Err(PipelineError::Decode(x))
 		    },
-		    _ => unreachable!(),
 		};
+		let buf_pos = pos;
+		pos += buf_to_send.len();
+		// cut off anything outside the part we're supposed to play
+		if pos > to_i {
+		    buf_to_send.truncate(to_i - buf_pos);
+		}
+		if buf_pos < from_i {
+		    if pos <= from_i { continue }
+		    buf_to_send.drain(.. from_i - buf_pos);
+		}
 		if let Err(_) = decode_tx.send(buf_to_send) { break }
 	    }
 	    trace!("Decoding completed");
+	    Ok(())
 	})?;
     let (loop_tx, loop_rx) = sync_channel(crate::NUM_PACKETS_BUFFERED);
-    let _ = std::thread::Builder::new().name("loop thread".to_string())
+    let loop_thread = std::thread::Builder::new()
+	.name("loop thread".to_string())
 	.spawn(move || {
-	    assert!(loop_right_i > loop_left_i); // not >=!
-	    let mut floats_left_till_start = loop_left_i;
+	    let mut floats_left_till_start = loop_left_i - from_i;
 	    let mut floats_left_till_end = loop_right_i - loop_left_i;
-	    let mut loop_buf = if loop_right_i == usize::MAX { Vec::new() }
-	    else { Vec::with_capacity(floats_left_till_end) };
+	    let mut loop_buf = LoopBuf::new(loop_limit,
+					    crossfade_i.max(holdback));
+	    // the part of the packet we were in when we hit the loop start
+	    let mut first = Vec::new();
 	    // the position of the next DECODED BUFFER we receive
-	    let mut pos = 0;
+	    let mut pos = from_i;
 	    while floats_left_till_start > 0 {
 		let mut floats = match decode_rx.recv() {
 		    Ok(x) => x,
-		    Err(_) => return,
+		    Err(_) => return Ok(()),
 		};
                 debug_assert!(floats.len() > 0);
 		if floats.len() <= floats_left_till_start {
 		    let floats_len = floats.len();
 		    floats_left_till_start -= floats_len;
-		    if let Err(_) = loop_tx.send((pos, floats)) { return }
+		    if let Err(_) = loop_tx.send((pos, floats.into())) {
+			return Ok(())
+		    }
 		    pos += floats_len;
 		}
 		else {
-		    loop_buf
-			.extend_from_slice(&floats[floats_left_till_start..]);
+		    first = floats[floats_left_till_start..].to_vec();
 		    let floats_len = floats.len();
 		    floats.resize(floats_left_till_start, 0.0);
                     debug_assert!(floats.len() > 0);
-		    if let Err(_) = loop_tx.send((pos, floats)) { return }
+		    if let Err(_) = loop_tx.send((pos, floats.into())) {
+			return Ok(())
+		    }
 		    pos += floats_len;
 		    break
 		}
@@ -185,24 +640,24 @@ pub fn start_decoding(path: &Path, terminator: Terminator)
 	    // once we've hit the left loop point, we want to race ahead
 	    // and find the right loop point as soon as possible. so, we
 	    // start buffering our sends.
-	    let mut buffered_sends = VecDeque::new();
-	    let mut rest = if loop_buf.len() > floats_left_till_end {
-		let rest: Vec<f32> = loop_buf[floats_left_till_end..]
-		    .iter().map(|x| *x).collect();
-		loop_buf.resize(floats_left_till_end, 0.0);
-		if let Err(_) = loop_tx.send((loop_left_i, loop_buf.clone())) {
-		    return
+	    let mut buffered_sends: VecDeque<(usize, Packet)> = VecDeque::new();
+	    let mut buffered_len = 0;
+	    let mut rest = if first.len() > floats_left_till_end {
+		let rest = first.split_off(floats_left_till_end);
+		loop_buf.extend_from_slice(&first);
+		if let Err(_) = loop_tx.send((loop_left_i, first.into())) {
+		    return Ok(())
 		}
 		rest
 	    }
 	    else {
-                if loop_buf.len() > 0 {
-		    if let Err(_) = loop_tx.send((loop_left_i,
-                                                  loop_buf.clone())) {
-		        return
+		loop_buf.extend_from_slice(&first);
+		floats_left_till_end -= first.len();
+                if first.len() > 0 {
+		    if let Err(_) = loop_tx.send((loop_left_i, first.into())) {
+			return Ok(())
 		    }
                 }
-		floats_left_till_end -= loop_buf.len();
 		loop {
 		    if floats_left_till_end == 0 { break vec![] }
 		    let mut floats = match decode_rx.recv() {
@@ -213,7 +668,8 @@ pub fn start_decoding(path: &Path, terminator: Terminator)
 			floats_left_till_end -= floats.len();
 			loop_buf.extend_from_slice(&floats[..]);
 			let floats_len = floats.len();
-			buffered_sends.push_back((pos, floats));
+			buffered_len += floats_len;
+			buffered_sends.push_back((pos, floats.into()));
 			pos += floats_len;
 		    }
 		    else {
@@ -224,29 +680,115 @@ pub fn start_decoding(path: &Path, terminator: Terminator)
 			let floats_len = floats.len();
 			floats.resize(floats_left_till_end, 0.0);
                         debug_assert!(floats.len() > 0);
-			buffered_sends.push_back((pos, floats));
+			buffered_len += floats.len();
+			buffered_sends.push_back((pos, floats.into()));
 			pos += floats_len;
 			break rest;
 		    }
 		    while let Some(buffered_send) = buffered_sends.pop_front(){
-			match loop_tx.try_send(buffered_send) {
-			    Ok(_) => (),
+			// for an ambient fade, anything within `holdback` of
+			// where we are might turn out to be the tail
+			if buffered_len - buffered_send.1.len() < holdback {
+			    buffered_sends.push_front(buffered_send);
+			    break;
+			}
+			let send_len = buffered_send.1.len();
+			let sent = if loop_buf.is_streaming() {
+			    // we'd run out of memory racing ahead to the end
+			    // of a loop this big, so just keep pace
+			    loop_tx.send(buffered_send)
+				.map_err(|x| TrySendError::Disconnected(x.0))
+			}
+			else { loop_tx.try_send(buffered_send) };
+			match sent {
+			    Ok(_) => buffered_len -= send_len,
 			    Err(TrySendError::Full(buffered_send)) => { 
 				buffered_sends.push_front(buffered_send);
 				break;
 			    },
-			    Err(_) => return,
+			    Err(_) => return Ok(()),
 			}
 		    }
 		}
 	    };
+	    if holdback > 0 {
+		// take the tail off the end of the loop. it gets lapped onto
+		// the start of the loop instead, and played as part of the
+		// ending when we're done looping.
+		let tail_len = holdback.min(buffered_len)
+		    .min(loop_buf.len() / 2)
+		    / channel_count as usize * channel_count as usize;
+		trace!("Ambient fade: holding back {} seconds",
+		       tail_len as f64 / time_unit as f64);
+		let mut tail = loop_buf.split_off(tail_len);
+		let mut to_remove = tail_len;
+		while to_remove > 0 {
+		    let last = match buffered_sends.back_mut() {
+			Some((_, x)) => x,
+			None => {
+			    terminator.terminate();
+			    return Err(PipelineError::Loop(anyhow!(
+				"the ambient tail is {} samples longer than \
+				 what was held back", to_remove)))
+			},
+		    };
+		    if last.len() <= to_remove {
+			to_remove -= last.len();
+			buffered_sends.pop_back();
+		    }
+		    else {
+			last.truncate(last.len() - to_remove);
+			to_remove = 0;
+		    }
+		}
+		tail.extend_from_slice(&rest);
+		rest = tail;
+		if tail_len > 0 {
+		    crossfade_i = tail_len;
+		    crossfade_curve = ambient_curve;
+		}
+	    }
 	    // we now know for sure the length of the loop!
 	    loop_right_atom.store(loop_left_i + loop_buf.len(),
 				  Ordering::Relaxed);
 	    // drain our buffered sends before we do any more work
 	    for buffered_send in buffered_sends.into_iter() {
-		if let Err(_) = loop_tx.send(buffered_send) { return }
+		if let Err(_) = loop_tx.send(buffered_send) { return Ok(()) }
 	    }
+	    let mut max_plays = max_plays;
+	    if let Some(duration_i) = duration_i {
+		// to pick the number of loops whose ending lands closest to
+		// the requested duration, we need to know how long the
+		// ending is
+		let outro = match end_i {
+		    Some(end_i) => end_i
+			.saturating_sub(loop_left_i + loop_buf.len()),
+		    None => {
+			// (a chained or unmeasurable stream; there's nothing
+			// for it but to decode the rest of it now)
+			while let Ok(x) = decode_rx.recv() {
+			    rest.extend_from_slice(&x);
+			}
+			rest.len()
+		    },
+		};
+		let plays = plays_for_duration(duration_i,
+					       (loop_left_i - from_i) + outro,
+					       loop_buf.len());
+		trace!("Playing the loop {} times to last {} seconds",
+		       plays, duration_i as f64 / time_unit as f64);
+		max_plays = Some(plays);
+	    }
+	    // the first time through the loop counts as a play; every time we
+	    // go around again is another one
+	    let mut plays = 1;
+	    let mut keep_looping = || {
+		if max_plays.is_some_and(|x| plays >= x) {
+		    terminator.stop_looping();
+		}
+		plays += 1;
+		terminator.should_loop()
+	    };
 	    if loop_mix {
 		// obscure feature, never before supported by any other imp-
 		// lementation of this "standard"!
@@ -254,19 +796,21 @@ pub fn start_decoding(path: &Path, terminator: Terminator)
 		// if `LOOP_MIX` is requested, then all audio after the loop
 		// gets back-mixed into the loop
 		let mut new_floats = rest.clone();
-		let mut old_floats = &mut loop_buf[..];
+		let mut old_floats = loop_buf.head_mut();
 		let mut pos = loop_left_i;
 		'outer: loop {
 		    // we've hit the loop point (or just barely started—cont-
 		    // inue only if looping is desired
-		    if !terminator.should_loop() { break }
-		    old_floats = &mut loop_buf[..];
+		    if !keep_looping() { break }
+		    old_floats = loop_buf.head_mut();
 		    pos = loop_left_i;
 		    while old_floats.len() > 0 {
 			if old_floats.len() < new_floats.len() {
 			    mix_onto(old_floats, &new_floats[..old_floats.len()]);
 			    let blah = old_floats.to_owned();
-			    if let Err(_) = loop_tx.send((pos, blah)) { return }
+			    if let Err(_) = loop_tx.send((pos, blah.into())) {
+				return Ok(())
+			    }
 			    pos += old_floats.len();
 			    new_floats.copy_within(old_floats.len().., 0);
 			    new_floats.resize(new_floats.len()-old_floats.len(), 0.0);
@@ -275,7 +819,9 @@ pub fn start_decoding(path: &Path, terminator: Terminator)
 			else {
 			    mix_onto(&mut old_floats[..new_floats.len()], &new_floats);
 			    let blah = old_floats[..new_floats.len()].to_owned();
-			    if let Err(_) = loop_tx.send((pos, blah)) { return }
+			    if let Err(_) = loop_tx.send((pos, blah.into())) {
+				return Ok(())
+			    }
 			    pos += new_floats.len();
 			    old_floats = &mut old_floats[new_floats.len()..];
 			    match decode_rx.recv() {
@@ -290,46 +836,303 @@ pub fn start_decoding(path: &Path, terminator: Terminator)
 		}
 		if old_floats.len() > 0 {
 		    for chunk in old_floats.chunks(4096) {
-			if let Err(_) = loop_tx.send((pos, chunk.to_owned())) { return }
+			let packet = chunk.to_vec().into();
+			if let Err(_) = loop_tx.send((pos, packet)) {
+			    return Ok(())
+			}
 			pos += chunk.len();
 		    }
 		}
 	    }
 	    else {
 		// without `LOOP_MIX`, cross-lap up to a few dozen samples
-		// around the loop point to remove the "pop"
-		let crosslap_amount = (DESIRED_CROSSLAP_AMOUNT
-				       * channel_count as usize)
-		    .min(loop_buf.len());
+		// (or however many were asked for) around the loop point to
+		// remove the "pop"
+		let crosslap_amount = crossfade_i.min(loop_buf.len());
 		while rest.len() < crosslap_amount {
 		    if let Ok(x) = decode_rx.recv() {
 			rest.extend_from_slice(&x);
 		    } else { break }
 		}
-		if rest.len() >= crosslap_amount {
-		    crosslap_onto(&mut loop_buf[..crosslap_amount],
+		// if the song ends too soon after the loop, lap what we can
+		let crosslap_amount = crosslap_amount.min(rest.len());
+		if crosslap_amount > 0 {
+		    crosslap_onto(&mut loop_buf.head_mut()[..crosslap_amount],
 				  &rest[..crosslap_amount],
-				  channel_count);
+				  channel_count, crossfade_curve);
 		}
 	    }
-	    while terminator.should_loop() {
+	    // every time around shares the same buffer, rather than copying it
+	    let head = loop_buf.share_head();
+	    // if the loop outgrew memory, the rest of it will have to come from
+	    // a second copy of the stream
+	    let mut rewinder = if loop_buf.is_streaming() {
+		match open(&rewinder_path, strict) {
+		    Ok(x) => {
+			let mut rewinder = x.source;
+			// it's compressed, so there's a good chance it'll fit
+			// after all
+			rewinder.cache_loops(loop_limit
+					     .saturating_mul(std::mem::size_of
+							     ::<f32>()));
+			Some(rewinder)
+		    },
+		    Err(x) => {
+			terminator.terminate();
+			return Err(PipelineError::Decode(x))
+		    },
+		}
+	    } else { None };
+	    while keep_looping() {
 		// four thousand ninety six? okay
 		let mut pos = loop_left_i;
-		for chunk in loop_buf.chunks(4096) {
-		    if let Err(_) = loop_tx.send((pos, chunk.iter().map(|x| *x)
-						  .collect())) { return }
-		    pos += chunk.len();
+		for start in (0 .. head.len()).step_by(4096) {
+		    let end = (start + 4096).min(head.len());
+		    let packet = Packet::share(&head, start .. end);
+		    if let Err(_) = loop_tx.send((pos, packet)) {
+			return Ok(())
+		    }
+		    pos += end - start;
+		}
+		// the rest of the loop has to come from the file again. (the
+		// start of it, we still have, already lapped.)
+		let rewinder = match rewinder.as_mut() {
+		    Some(x) => x,
+		    None => continue,
+		};
+		let mut to_skip = pos - loop_left_i;
+		let loop_end_i = loop_left_i + loop_buf.len();
+		if let Err(x) = rewinder.seek(loop_left) {
+		    terminator.terminate();
+		    return Err(PipelineError::Decode(x))
+		}
+		while pos < loop_end_i {
+		    let mut floats = match rewinder.next_packet() {
+			Ok(Some(x)) => x,
+			Ok(None) => break,
+			Err(x) => {
+			    terminator.terminate();
+			    return Err(PipelineError::Decode(x))
+			},
+		    };
+		    let skipped = to_skip.min(floats.len());
+		    floats.drain(..skipped);
+		    to_skip -= skipped;
+		    floats.truncate(loop_end_i - pos);
+		    if floats.is_empty() { continue }
+		    let floats_len = floats.len();
+		    if let Err(_) = loop_tx.send((pos, floats.into())) {
+			return Ok(())
+		    }
+		    pos += floats_len;
 		}
 	    }
             if rest.len() > 0 {
-	        if let Err(_) = loop_tx.send((loop_right_i, rest)) { return }
+	        if let Err(_) = loop_tx.send((loop_left_i + loop_buf.len(),
+					      rest.into())) { return Ok(()) }
             }
 	    while let Ok(x) = decode_rx.recv() {
 		let x_len = x.len();
-		if let Err(_) = loop_tx.send((pos, x)) { return }
+		if let Err(_) = loop_tx.send((pos, x.into())) { return Ok(()) }
 		pos += x_len;
 	    }
+	    Ok(())
 	})?;
     Ok((sample_rate, channel_count, loop_left_i, loop_right_atom_clone,
-	loop_rx))
+	end_i, loop_rx, Workers::new(vec![decode_thread, loop_thread])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> Result<Time, String> { s.parse() }
+
+    #[test]
+    fn seconds() {
+	assert_eq!(time("12.5"), Ok(Time::Seconds(12.5)));
+	assert_eq!(time(" 0 "), Ok(Time::Seconds(0.0)));
+	assert_eq!(time("1:02.5"), Ok(Time::Seconds(62.5)));
+	assert_eq!(time("1:00:02.5"), Ok(Time::Seconds(3602.5)));
+    }
+
+    #[test]
+    fn samples() {
+	assert_eq!(time("44100s"), Ok(Time::Samples(44100)));
+	assert_eq!(time("44100smp"), Ok(Time::Samples(44100)));
+	assert!(time("1.5s").is_err());
+	assert!(time("s").is_err());
+    }
+
+    #[test]
+    fn bad_times() {
+	for bad in ["", "soon", "-1", "inf", "NaN", "1:-2", "1.5:00",
+		    "1:00:00:00", "::5"] {
+	    assert!(time(bad).is_err(), "{:?} parsed", bad);
+	}
+    }
+
+    #[test]
+    fn to_frames() {
+	// a time between two samples rounds up to the later one
+	assert_eq!(Time::Seconds(0.5).to_frames(44100), 22050);
+	assert_eq!(Time::Seconds(1.0 / 3.0).to_frames(44100), 14700);
+	assert_eq!(Time::Samples(123).to_frames(44100), 123);
+    }
+
+    #[test]
+    fn duration() {
+	// a 10-second intro and outro around a 60-second loop
+	assert_eq!(plays_for_duration(300, 10, 60), 5);
+	assert_eq!(plays_for_duration(320, 10, 60), 5);
+	assert_eq!(plays_for_duration(345, 10, 60), 6);
+	// too short for even one play, but there has to be one
+	assert_eq!(plays_for_duration(5, 10, 60), 1);
+	assert_eq!(plays_for_duration(300, 10, 0), 290);
+    }
+
+    fn loop_points(comments: &[(&str, &str)]) -> (usize, usize, bool) {
+	let x = LoopPoints::from_comments(comments, 44100).unwrap();
+	(x.left, x.right, x.mix)
+    }
+
+    #[test]
+    fn loop_comments() {
+	// FLAC tools tend to write the keys in capitals
+	assert_eq!(loop_points(&[("LOOPSTART", "1000"), ("LOOPLENGTH", "500")]),
+		   (1000, 1500, false));
+	assert_eq!(loop_points(&[("loop_start", "1.5"), ("Loop_End", "2"),
+				 ("LOOP_MIX", "")]),
+		   (66150, 88200, true));
+	// too big to be seconds, so taken as samples
+	assert_eq!(loop_points(&[("LOOP_START", "88200")]),
+		   (88200, usize::MAX, false));
+	// the seconds-based tags win
+	assert_eq!(loop_points(&[("LOOPSTART", "1000"), ("LOOP_START", "1")]),
+		   (44100, usize::MAX, false));
+	assert_eq!(loop_points(&[("TITLE", "LOOPSTART=5")]),
+		   (0, usize::MAX, false));
+	assert_eq!(LoopPoints::from_comments(&[("LOOP_CROSSFADE", "0.5")],
+					     44100).unwrap().crossfade,
+		   Some(22050));
+	for bad in [("LOOPSTART", "1.5"), ("LOOP_END", "soon"),
+		    ("LOOPLENGTH", "-1"), ("LOOP_CROSSFADE", "")] {
+	    assert!(LoopPoints::from_comments(&[bad], 44100).is_err(),
+		    "{:?}", bad);
+	}
+    }
+
+    #[test]
+    fn length_follows_start() {
+	let mut loop_points = LoopPoints { left: 100, right: 1000,
+					   mix: true, crossfade: None };
+	LoopOverrides {
+	    start: Some(Time::Seconds(1.0)),
+	    length: Some(Time::Samples(500)),
+	    mix: Some(false),
+	    ..Default::default()
+	}.apply(&mut loop_points, 44100);
+	assert_eq!((loop_points.left, loop_points.right, loop_points.mix),
+		   (44100, 44600, false));
+    }
+
+    #[test]
+    fn crossfade_curves() {
+	use CrossfadeCurve::*;
+	assert_eq!("equal-power".parse(), Ok(EqualPower));
+	assert!("cosine".parse::<CrossfadeCurve>().is_err());
+	let close = |a: f32, b: f32| (a - b).abs() < 1e-6;
+	for curve in [Linear, EqualPower, RaisedCosine] {
+	    let (fade_in, fade_out) = curve.gains(0.0);
+	    assert!(close(fade_in, 0.0) && close(fade_out, 1.0), "{:?}", curve);
+	    let (fade_in, fade_out) = curve.gains(1.0);
+	    assert!(close(fade_in, 1.0) && close(fade_out, 0.0), "{:?}", curve);
+	    for n in 0 ..= 20 {
+		let x = n as f32 / 20.0;
+		let (fade_in, fade_out) = curve.gains(x);
+		// each side mirrors the other
+		let (mirror_in, mirror_out) = curve.gains(1.0 - x);
+		assert!(close(fade_in, mirror_out), "{:?} at {}", curve, x);
+		assert!(close(fade_out, mirror_in), "{:?} at {}", curve, x);
+		if curve == EqualPower {
+		    assert!(close(fade_in.powi(2) + fade_out.powi(2), 1.0));
+		}
+		else {
+		    assert!(close(fade_in + fade_out, 1.0));
+		}
+	    }
+	}
+	// raised-cosine starts and ends flatter than linear
+	assert!(RaisedCosine.gains(0.1).0 < Linear.gains(0.1).0);
+	assert!(RaisedCosine.gains(0.9).0 > Linear.gains(0.9).0);
+    }
+
+    #[test]
+    fn crosslap() {
+	// stereo, so each gain covers two samples, taken from the middle of
+	// its frame
+	let mut o = [1.0, 2.0, 1.0, 2.0, 1.0, 2.0, 1.0, 2.0];
+	let i = [0.0, 0.0, 0.0, 0.0, 4.0, 4.0, 4.0, 4.0];
+	crosslap_onto(&mut o, &i, 2, CrossfadeCurve::Linear);
+	assert_eq!(o, [0.125, 0.25, 0.375, 0.75, 2.125, 2.75, 1.375, 2.25]);
+    }
+
+    fn floats(range: std::ops::RangeInclusive<u16>) -> Vec<f32> {
+	range.map(f32::from).collect()
+    }
+
+    #[test]
+    fn loop_buf_in_memory() {
+	let mut loop_buf = LoopBuf::new(10, 3);
+	loop_buf.extend_from_slice(&floats(1 ..= 4));
+	loop_buf.extend_from_slice(&floats(5 ..= 10));
+	assert!(!loop_buf.is_streaming());
+	assert_eq!(loop_buf.len(), 10);
+	assert_eq!(loop_buf.split_off(2), floats(9 ..= 10));
+	assert_eq!(loop_buf.len(), 8);
+	assert_eq!(loop_buf.head_mut(), &floats(1 ..= 8)[..]);
+    }
+
+    #[test]
+    fn loop_buf_streaming() {
+	let mut loop_buf = LoopBuf::new(10, 3);
+	loop_buf.extend_from_slice(&floats(1 ..= 8));
+	loop_buf.extend_from_slice(&floats(9 ..= 12));
+	// only the ends are left
+	assert!(loop_buf.is_streaming());
+	assert_eq!(loop_buf.head_mut(), &floats(1 ..= 3)[..]);
+	assert_eq!(loop_buf.tail.as_deref(), Some(&floats(10 ..= 12)[..]));
+	// the tail may run long for a while, but always ends with the loop
+	loop_buf.extend_from_slice(&floats(13 ..= 20));
+	loop_buf.extend_from_slice(&floats(21 ..= 21));
+	assert_eq!(loop_buf.tail.as_deref(), Some(&floats(19 ..= 21)[..]));
+	assert_eq!(loop_buf.len(), 21);
+	assert_eq!(loop_buf.split_off(2), floats(20 ..= 21));
+	assert_eq!(loop_buf.len(), 19);
+	assert_eq!(loop_buf.head_mut(), &floats(1 ..= 3)[..]);
+    }
+
+    #[test]
+    fn loop_buf_keeps_both_ends() {
+	// the limit is raised so that the head and tail can't overlap
+	let mut loop_buf = LoopBuf::new(2, 3);
+	loop_buf.extend_from_slice(&floats(1 ..= 6));
+	assert!(!loop_buf.is_streaming());
+	loop_buf.extend_from_slice(&floats(7 ..= 7));
+	assert!(loop_buf.is_streaming());
+	assert_eq!(loop_buf.split_off(3), floats(5 ..= 7));
+	assert_eq!(loop_buf.head_mut(), &floats(1 ..= 3)[..]);
+    }
+
+    #[test]
+    fn loop_buf_share_head() {
+	let mut loop_buf = LoopBuf::new(10, 3);
+	loop_buf.extend_from_slice(&floats(1 ..= 5));
+	let head = loop_buf.share_head();
+	assert_eq!(*head, floats(1 ..= 5));
+	// (taken, not copied)
+	assert!(loop_buf.head_mut().is_empty());
+	let packet = Packet::share(&head, 3 .. 5);
+	assert_eq!(&packet[..], &floats(4 ..= 5)[..]);
+    }
 }
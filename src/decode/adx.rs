@@ -0,0 +1,253 @@
+//! CRI ADX, with loops from the version 3 or 4 header.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+};
+
+use anyhow::anyhow;
+use log::trace;
+
+use super::{bytes::{read_u16_be, read_u32_be}, LoopPoints, Source, Stream};
+
+/// The only encoding type we handle: ADPCM with a prediction filter derived
+/// from the highpass frequency. (2 has fixed coefficients, 4 uses an
+/// exponential scale, and 0x10/0x11 are AHX.)
+const ENCODING_STANDARD: u8 = 3;
+
+/// How many ADX frames we decode at a time, per channel.
+const FRAMES_PER_PACKET: usize = 128;
+
+struct AdxChannel {
+    hist1: i32,
+    hist2: i32,
+}
+
+struct AdxSource {
+    file: BufReader<File>,
+    channels: Vec<AdxChannel>,
+    coef1: i32,
+    coef2: i32,
+    frame_bytes: usize,
+    frames_left: usize,
+}
+
+/// Finds the loop in an ADX header (all of it, up to the copyright string),
+/// if it has one. Returns the start and end sample.
+fn parse_loop(header: &[u8], version: u8, channel_count: u32)
+	      -> anyhow::Result<Option<(usize, usize)>> {
+    // version 3 and 4 have the same loop info, in different places, and only
+    // if the header is long enough to hold it. version 4 puts it after the
+    // ADPCM history, which takes at least 8 bytes, 4 per channel.
+    let loop_info_at = match version {
+	3 => Some(0x14),
+	4 => Some(0x18 + (4 * channel_count as usize).max(8)),
+	_ => None,
+    }.filter(|at| at + 0x18 <= header.len() - 6);
+    let at = match loop_info_at {
+	Some(at) if read_u32_be(header, at + 4) != 0 => at,
+	_ => return Ok(None),
+    };
+    let start = read_u32_be(header, at + 8);
+    let end = read_u32_be(header, at + 16);
+    if end <= start {
+	return Err(anyhow!("ADX loop ends ({}) before it starts ({})",
+			   end, start))
+    }
+    Ok(Some((start as usize, end as usize)))
+}
+
+pub fn open(mut file: File) -> anyhow::Result<Stream> {
+    let mut header = [0u8; 4];
+    file.read_exact(&mut header)?;
+    if read_u16_be(&header, 0) != 0x8000 {
+	return Err(anyhow!("not an ADX file"))
+    }
+    // the header runs up to the "(c)CRI" copyright string, which is followed
+    // by the audio data
+    let data_offset = read_u16_be(&header, 2) as usize + 4;
+    if data_offset < 0x20 {
+	return Err(anyhow!("ADX header is too short"))
+    }
+    let mut header = vec![0u8; data_offset];
+    file.read_exact(&mut header[4..])?;
+    if &header[data_offset - 6 .. data_offset] != b"(c)CRI" {
+	return Err(anyhow!("ADX header lacks a copyright string"))
+    }
+    let encoding = header[4];
+    let frame_bytes = header[5] as usize;
+    let bit_depth = header[6];
+    let channel_count = header[7] as u32;
+    let sample_rate = read_u32_be(&header, 8);
+    let sample_count = read_u32_be(&header, 12);
+    let highpass = read_u16_be(&header, 16);
+    let version = header[18];
+    let flags = header[19];
+    trace!("ADX: encoding {}, {}-byte frames, {}-bit, {} channels, {}Hz, \
+	    {} samples, highpass {}Hz, version {}, flags {}", encoding,
+	   frame_bytes, bit_depth, channel_count, sample_rate, sample_count,
+	   highpass, version, flags);
+    if encoding != ENCODING_STANDARD {
+	return Err(anyhow!("unhandled ADX encoding type: {}", encoding))
+    }
+    if bit_depth != 4 || frame_bytes <= 2 {
+	return Err(anyhow!("unhandled ADX frame layout: {} bytes of {}-bit \
+			    samples", frame_bytes, bit_depth))
+    }
+    if flags & 0x08 != 0 {
+	return Err(anyhow!("encrypted ADX files are not supported"))
+    }
+    if channel_count == 0 {
+	return Err(anyhow!("stream says it has no channels"))
+    }
+    if sample_rate == 0 {
+	return Err(anyhow!("stream says it's 0Hz, that unpossible"))
+    }
+    let mut loop_points = LoopPoints { left: 0, right: usize::MAX,
+				       mix: false,
+				       crossfade: None };
+    match parse_loop(&header, version, channel_count)? {
+	Some((start, end)) => {
+	    trace!("ADX loop: from {} to {}", start, end);
+	    loop_points.left = start;
+	    loop_points.right = end;
+	},
+	None => trace!("no ADX loop info, looping the whole stream"),
+    }
+    // the prediction filter is derived from the highpass frequency
+    let a = std::f64::consts::SQRT_2
+	- (2.0 * std::f64::consts::PI * highpass as f64 / sample_rate as f64)
+	.cos();
+    let b = std::f64::consts::SQRT_2 - 1.0;
+    let c = (a - ((a + b) * (a - b)).sqrt()) / b;
+    let coef1 = (c * 8192.0).floor() as i32;
+    let coef2 = (c * c * -4096.0).floor() as i32;
+    Ok(Stream {
+	sample_rate, channel_count, loop_points,
+	length: Some(sample_count as usize),
+	source: Box::new(AdxSource {
+	    file: BufReader::new(file),
+	    channels: (0 .. channel_count)
+		.map(|_| AdxChannel { hist1: 0, hist2: 0 }).collect(),
+	    coef1, coef2, frame_bytes,
+	    frames_left: sample_count as usize,
+	}),
+    })
+}
+
+impl Source for AdxSource {
+    fn next_packet(&mut self) -> anyhow::Result<Option<Vec<f32>>> {
+	let channel_count = self.channels.len();
+	let samples_per_frame = (self.frame_bytes - 2) * 2;
+	let frame_count = FRAMES_PER_PACKET
+	    .min(self.frames_left.div_ceil(samples_per_frame));
+	if frame_count == 0 { return Ok(None) }
+	let mut in_buf = vec![0u8; frame_count * self.frame_bytes
+			      * channel_count];
+	self.file.read_exact(&mut in_buf)?;
+	let sample_count = (frame_count * samples_per_frame)
+	    .min(self.frames_left);
+	let mut out_buf = vec![0.0; sample_count * channel_count];
+	// frames are interleaved channel by channel
+	for (n, frame) in in_buf.chunks_exact(self.frame_bytes).enumerate() {
+	    let channel_index = n % channel_count;
+	    let channel = &mut self.channels[channel_index];
+	    let scale = (read_u16_be(frame, 0) & 0x1FFF) as i32 + 1;
+	    let first_sample = (n / channel_count) * samples_per_frame;
+	    let outs = out_buf.iter_mut()
+		.skip(first_sample * channel_count + channel_index)
+		.step_by(channel_count);
+	    let nybbles = frame[2..].iter()
+		.flat_map(|&byte| [byte >> 4, byte & 0xF]);
+	    for (o, nybble) in outs.zip(nybbles) {
+		// sign-extend the nybble
+		let nybble = ((nybble as i8) << 4 >> 4) as i32;
+		let sample = nybble * scale
+		    + ((self.coef1 * channel.hist1
+			+ self.coef2 * channel.hist2) >> 12);
+		let sample = sample.clamp(i16::MIN as i32, i16::MAX as i32);
+		channel.hist2 = channel.hist1;
+		channel.hist1 = sample;
+		*o = sample as f32 / 32768.0;
+	    }
+	}
+	self.frames_left -= sample_count;
+	Ok(Some(out_buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a header like CRI's encoder would, with the loop block at
+    /// `loop_at` and the copyright string ending at `data_offset`.
+    fn header(version: u8, channel_count: u8, loop_at: usize,
+	      data_offset: usize, start: u32, end: u32) -> Vec<u8> {
+	let mut ret = vec![0u8; data_offset];
+	ret[0..2].copy_from_slice(&0x8000u16.to_be_bytes());
+	ret[2..4].copy_from_slice(&(data_offset as u16 - 4).to_be_bytes());
+	ret[4..8].copy_from_slice(&[ENCODING_STANDARD, 18, 4, channel_count]);
+	ret[8..12].copy_from_slice(&44100u32.to_be_bytes());
+	ret[12..16].copy_from_slice(&1000000u32.to_be_bytes());
+	ret[16..18].copy_from_slice(&500u16.to_be_bytes());
+	ret[18] = version;
+	// loop enabled, then start sample, start byte, end sample, end byte
+	ret[loop_at + 4 .. loop_at + 8].copy_from_slice(&1u32.to_be_bytes());
+	ret[loop_at + 8 .. loop_at + 12].copy_from_slice(&start.to_be_bytes());
+	ret[loop_at + 12 .. loop_at + 16]
+	    .copy_from_slice(&(start / 32 * 36).to_be_bytes());
+	ret[loop_at + 16 .. loop_at + 20].copy_from_slice(&end.to_be_bytes());
+	ret[loop_at + 20 .. loop_at + 24]
+	    .copy_from_slice(&(end / 32 * 36).to_be_bytes());
+	ret[data_offset - 6 ..].copy_from_slice(b"(c)CRI");
+	ret
+    }
+
+    #[test]
+    fn version_3() {
+	let hdr = header(3, 2, 0x14, 0x34, 88200, 441000);
+	assert_eq!(parse_loop(&hdr, 3, 2).unwrap(), Some((88200, 441000)));
+    }
+
+    #[test]
+    fn version_4_stereo() {
+	let hdr = header(4, 2, 0x20, 0x44, 88200, 441000);
+	assert_eq!(parse_loop(&hdr, 4, 2).unwrap(), Some((88200, 441000)));
+    }
+
+    #[test]
+    fn version_4_mono() {
+	let hdr = header(4, 1, 0x20, 0x44, 1234, 5678);
+	assert_eq!(parse_loop(&hdr, 4, 1).unwrap(), Some((1234, 5678)));
+    }
+
+    #[test]
+    fn version_4_surround() {
+	// six channels of history push the loop block back
+	let hdr = header(4, 6, 0x30, 0x54, 1234, 5678);
+	assert_eq!(parse_loop(&hdr, 4, 6).unwrap(), Some((1234, 5678)));
+    }
+
+    #[test]
+    fn not_looping() {
+	let mut hdr = header(4, 2, 0x20, 0x44, 1234, 5678);
+	hdr[0x24 .. 0x28].fill(0);
+	assert_eq!(parse_loop(&hdr, 4, 2).unwrap(), None);
+    }
+
+    #[test]
+    fn too_short_for_loop() {
+	// no room for the loop block before the copyright string
+	let mut hdr = header(3, 2, 0x14, 0x34, 1234, 5678);
+	hdr.truncate(0x1A);
+	hdr.extend_from_slice(b"(c)CRI");
+	assert_eq!(parse_loop(&hdr, 3, 2).unwrap(), None);
+    }
+
+    #[test]
+    fn backwards_loop() {
+	let hdr = header(3, 2, 0x14, 0x34, 5678, 1234);
+	assert!(parse_loop(&hdr, 3, 2).is_err());
+    }
+}
@@ -0,0 +1,382 @@
+//! AIFF and AIFF-C, with loops from the `INST` chunk's sustain loop.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+};
+
+use anyhow::anyhow;
+use log::{trace, warn};
+
+use super::{bytes::{read_u16_be, read_u32_be}, LoopPoints, Source, Stream};
+
+/// How many frames we read at a time.
+const FRAMES_PER_PACKET: usize = 4096;
+
+#[derive(Debug,Clone,Copy)]
+enum SampleFormat {
+    /// Signed integers of this many bytes, big-endian unless `little`.
+    /// (Samples with an odd number of bits are left-justified, so we can
+    /// treat them as if they were the full width.)
+    Int { bytes: usize, little: bool },
+    F32,
+    F64,
+}
+
+impl SampleFormat {
+    fn bytes(&self) -> usize {
+	match self {
+	    SampleFormat::Int { bytes, .. } => *bytes,
+	    SampleFormat::F32 => 4,
+	    SampleFormat::F64 => 8,
+	}
+    }
+    fn decode(&self, x: &[u8]) -> f32 {
+	match self {
+	    SampleFormat::Int { bytes, little } => {
+		// pile the bytes into the top of an i32
+		let mut val = [0u8; 4];
+		if *little {
+		    for n in 0 .. *bytes { val[n] = x[bytes - 1 - n]; }
+		}
+		else {
+		    val[..*bytes].copy_from_slice(x);
+		}
+		i32::from_be_bytes(val) as f32 / 2147483648.0
+	    },
+	    SampleFormat::F32 => f32::from_be_bytes([x[0], x[1], x[2], x[3]]),
+	    SampleFormat::F64 => f64::from_be_bytes([x[0], x[1], x[2], x[3],
+						     x[4], x[5], x[6], x[7]])
+		as f32,
+	}
+    }
+}
+
+struct AiffSource {
+    file: BufReader<File>,
+    format: SampleFormat,
+    channel_count: usize,
+    frames_left: u64,
+}
+
+/// Converts an 80-bit IEEE 754 extended precision number, which is how AIFF
+/// stores its sample rate, into something more reasonable.
+fn read_extended(buf: &[u8]) -> f64 {
+    let sign = if buf[0] & 0x80 != 0 { -1.0 } else { 1.0 };
+    let exponent = (read_u16_be(buf, 0) & 0x7FFF) as i32;
+    let mantissa = u64::from_be_bytes([buf[2], buf[3], buf[4], buf[5],
+				       buf[6], buf[7], buf[8], buf[9]]);
+    if exponent == 0 && mantissa == 0 { return 0.0 }
+    sign * mantissa as f64 * 2f64.powi(exponent - 16383 - 63)
+}
+
+/// (channel count, frame count, sample rate, sample format)
+fn parse_comm(comm: &[u8], is_aifc: bool)
+	      -> anyhow::Result<(u32, u64, u32, SampleFormat)> {
+    if comm.len() < 18 || (is_aifc && comm.len() < 22) {
+	return Err(anyhow!("malformed COMM chunk"))
+    }
+    let channel_count = read_u16_be(comm, 0) as u32;
+    let frame_count = read_u32_be(comm, 2) as u64;
+    let sample_size = read_u16_be(comm, 6);
+    let sample_rate = read_extended(&comm[8..18]);
+    let compression_type = if is_aifc { &comm[18..22] } else { b"NONE" };
+    let int_bytes = (sample_size as usize).div_ceil(8);
+    let format = match compression_type {
+	b"NONE" | b"twos" if (1..=4).contains(&int_bytes) => {
+	    SampleFormat::Int { bytes: int_bytes, little: false }
+	},
+	b"sowt" if (1..=4).contains(&int_bytes) => {
+	    SampleFormat::Int { bytes: int_bytes, little: true }
+	},
+	b"fl32" | b"FL32" => SampleFormat::F32,
+	b"fl64" | b"FL64" => SampleFormat::F64,
+	x => return Err(anyhow!("unhandled AIFF-C compression type {:?} with \
+				 {} bits per sample",
+				String::from_utf8_lossy(x), sample_size)),
+    };
+    if channel_count == 0 {
+	return Err(anyhow!("stream says it has no channels"))
+    }
+    if !(1.0 .. 1048576.0).contains(&sample_rate) {
+	return Err(anyhow!("stream says it's {}Hz, that unpossible",
+			   sample_rate))
+    }
+    Ok((channel_count, frame_count, sample_rate.round() as u32, format))
+}
+
+/// Returns (marker ID, position, name) for every marker.
+fn parse_mark(mark: &[u8]) -> anyhow::Result<Vec<(u16, u32, String)>> {
+    let bad_mark = || anyhow!("malformed MARK chunk");
+    if mark.len() < 2 { return Err(bad_mark()) }
+    let count = read_u16_be(mark, 0);
+    let mut pos = 2;
+    let mut ret = Vec::with_capacity(count as usize);
+    for _ in 0 .. count {
+	if mark.len() < pos + 7 { return Err(bad_mark()) }
+	let id = read_u16_be(mark, pos);
+	let position = read_u32_be(mark, pos + 2);
+	let name_len = mark[pos + 6] as usize;
+	pos += 7;
+	if mark.len() < pos + name_len { return Err(bad_mark()) }
+	let name = String::from_utf8_lossy(&mark[pos .. pos + name_len])
+	    .into_owned();
+	// the length byte plus the string are padded to an even length
+	pos += name_len + (!name_len & 1);
+	ret.push((id, position, name));
+    }
+    Ok(ret)
+}
+
+/// Returns (play mode, begin marker, end marker) for the sustain loop and
+/// the release loop.
+fn parse_inst(inst: &[u8]) -> anyhow::Result<[(u16, u16, u16); 2]> {
+    if inst.len() < 20 { return Err(anyhow!("malformed INST chunk")) }
+    let read_loop = |at| (read_u16_be(inst, at), read_u16_be(inst, at + 2),
+			  read_u16_be(inst, at + 4));
+    Ok([read_loop(8), read_loop(14)])
+}
+
+/// Looks up the sustain loop from an `INST` chunk in the markers from the
+/// `MARK` chunk, returning (begin, end). Logs the release loop.
+fn sustain_loop([sustain_loop, release_loop]: [(u16, u16, u16); 2],
+		markers: &[(u16, u32, String)])
+		-> anyhow::Result<Option<(u32, u32)>> {
+    let find_marker = |id: u16| -> anyhow::Result<u32> {
+	match markers.iter().find(|x| x.0 == id) {
+	    Some(x) => Ok(x.1),
+	    None => Err(anyhow!("INST loop refers to nonexistent marker {}",
+				id)),
+	}
+    };
+    let (play_mode, begin, end) = release_loop;
+    if play_mode != 0 {
+	// we're not playing it, so it doesn't matter if it's broken
+	let describe = |id| match find_marker(id) {
+	    Ok(x) => x.to_string(),
+	    Err(_) => format!("nonexistent marker {}", id),
+	};
+	warn!("Ignoring release loop: from {} to {}", describe(begin),
+	      describe(end));
+    }
+    let (play_mode, begin, end) = sustain_loop;
+    if play_mode == 0 { return Ok(None) }
+    let begin = find_marker(begin)?;
+    let end = find_marker(end)?;
+    if end <= begin {
+	return Err(anyhow!("sustain loop ends ({}) before it starts ({})",
+			   end, begin))
+    }
+    if play_mode == 2 {
+	warn!("sustain loop is a forward-backward loop, we will play it \
+	       forward only");
+    }
+    trace!("Sustain loop: from {} to {}", begin, end);
+    Ok(Some((begin, end)))
+}
+
+pub fn open(mut file: File) -> anyhow::Result<Stream> {
+    let mut header = [0u8; 12];
+    file.read_exact(&mut header)?;
+    if &header[0..4] != b"FORM" {
+	return Err(anyhow!("not an IFF file"))
+    }
+    let is_aifc = match &header[8..12] {
+	b"AIFF" => false,
+	b"AIFC" => true,
+	_ => return Err(anyhow!("not an AIFF or AIFF-C file")),
+    };
+    let mut comm = None;
+    let mut ssnd_offset = None;
+    let mut markers = Vec::new();
+    let mut inst = None;
+    loop {
+	let mut chunk_header = [0u8; 8];
+	match file.read_exact(&mut chunk_header) {
+	    Ok(_) => (),
+	    Err(x) if x.kind() == std::io::ErrorKind::UnexpectedEof => break,
+	    Err(x) => return Err(x.into()),
+	}
+	let id = &chunk_header[0..4];
+	let len = read_u32_be(&chunk_header, 4) as u64;
+	// chunks are padded to an even length
+	let padded_len = len + (len & 1);
+	trace!("AIFF chunk {:?}, {} bytes", String::from_utf8_lossy(id), len);
+	if id == b"SSND" {
+	    let mut ssnd_header = [0u8; 8];
+	    file.read_exact(&mut ssnd_header)?;
+	    let data_offset = file.stream_position()?
+		+ read_u32_be(&ssnd_header, 0) as u64;
+	    ssnd_offset = Some(data_offset);
+	    file.seek(SeekFrom::Current(padded_len as i64 - 8))?;
+	    continue
+	}
+	match id {
+	    b"COMM" | b"MARK" | b"INST" => (),
+	    _ => {
+		file.seek(SeekFrom::Current(padded_len as i64))?;
+		continue
+	    },
+	}
+	let mut chunk = vec![0u8; len as usize];
+	file.read_exact(&mut chunk)?;
+	if len & 1 != 0 { file.seek(SeekFrom::Current(1))?; }
+	match id {
+	    b"COMM" => comm = Some(parse_comm(&chunk, is_aifc)?),
+	    b"MARK" => markers = parse_mark(&chunk)?,
+	    b"INST" => inst = Some(parse_inst(&chunk)?),
+	    _ => (),
+	}
+    }
+    let (channel_count, frame_count, sample_rate, format) = match comm {
+	Some(x) => x,
+	None => return Err(anyhow!("AIFF file has no COMM chunk")),
+    };
+    let data_offset = match ssnd_offset {
+	Some(x) => x,
+	None => return Err(anyhow!("AIFF file has no SSND chunk")),
+    };
+    for (id, position, name) in markers.iter() {
+	trace!("Marker {} {:?}: at {}", id, name, position);
+    }
+    let mut loop_points = LoopPoints { left: 0, right: usize::MAX,
+				       mix: false,
+				       crossfade: None };
+    if let Some(inst) = inst {
+	if let Some((begin, end)) = sustain_loop(inst, &markers)? {
+	    // marker positions are between samples, so the end marker is
+	    // already the first sample "not in" the loop
+	    loop_points.left = begin as usize;
+	    loop_points.right = end as usize;
+	}
+    }
+    file.seek(SeekFrom::Start(data_offset))?;
+    Ok(Stream {
+	sample_rate, channel_count, loop_points,
+	length: Some(frame_count as usize),
+	source: Box::new(AiffSource {
+	    file: BufReader::new(file), format,
+	    channel_count: channel_count as usize,
+	    frames_left: frame_count,
+	}),
+    })
+}
+
+impl Source for AiffSource {
+    fn next_packet(&mut self) -> anyhow::Result<Option<Vec<f32>>> {
+	let frame_count = (FRAMES_PER_PACKET as u64).min(self.frames_left)
+	    as usize;
+	if frame_count == 0 { return Ok(None) }
+	let mut in_buf = vec![0u8; frame_count * self.format.bytes()
+			      * self.channel_count];
+	match self.file.read_exact(&mut in_buf) {
+	    Ok(_) => (),
+	    Err(x) if x.kind() == std::io::ErrorKind::UnexpectedEof => {
+		warn!("AIFF file is shorter than its COMM chunk says");
+		self.frames_left = 0;
+		return Ok(None)
+	    },
+	    Err(x) => return Err(x.into()),
+	}
+	self.frames_left -= frame_count as u64;
+	Ok(Some(in_buf.chunks_exact(self.format.bytes())
+		.map(|x| self.format.decode(x)).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `MARK` chunk with the given (ID, position, name) markers.
+    fn mark(markers: &[(u16, u32, &str)]) -> Vec<u8> {
+	let mut ret = (markers.len() as u16).to_be_bytes().to_vec();
+	for &(id, position, name) in markers.iter() {
+	    ret.extend_from_slice(&id.to_be_bytes());
+	    ret.extend_from_slice(&position.to_be_bytes());
+	    ret.push(name.len() as u8);
+	    ret.extend_from_slice(name.as_bytes());
+	    if name.len() & 1 == 0 { ret.push(0) }
+	}
+	ret
+    }
+
+    /// An `INST` chunk with the given (play mode, begin, end) loops.
+    fn inst(sustain: (u16, u16, u16), release: (u16, u16, u16)) -> Vec<u8> {
+	let mut ret = vec![60, 0, 0, 127, 0, 127, 0, 0];
+	for (play_mode, begin, end) in [sustain, release] {
+	    for x in [play_mode, begin, end] {
+		ret.extend_from_slice(&x.to_be_bytes());
+	    }
+	}
+	ret
+    }
+
+    #[test]
+    fn sample_rate() {
+	let rate = [0x40, 0x0E, 0xAC, 0x44, 0, 0, 0, 0, 0, 0];
+	assert_eq!(read_extended(&rate), 44100.0);
+	assert_eq!(read_extended(&[0; 10]), 0.0);
+    }
+
+    #[test]
+    fn markers() {
+	// odd and even name lengths pad differently
+	let chunk = mark(&[(1, 4410, "Loop"), (2, 88200, "End"), (7, 0, "")]);
+	assert_eq!(parse_mark(&chunk).unwrap(), vec![
+	    (1, 4410, "Loop".to_owned()),
+	    (2, 88200, "End".to_owned()),
+	    (7, 0, String::new()),
+	]);
+    }
+
+    #[test]
+    fn truncated_markers() {
+	let mut chunk = mark(&[(1, 4410, "Loop"), (2, 88200, "End")]);
+	chunk.truncate(chunk.len() - 3);
+	assert!(parse_mark(&chunk).is_err());
+    }
+
+    #[test]
+    fn forward_loop() {
+	let markers = parse_mark(&mark(&[(1, 4410, "start"),
+					  (2, 88200, "end")])).unwrap();
+	let loops = parse_inst(&inst((1, 1, 2), (0, 0, 0))).unwrap();
+	assert_eq!(sustain_loop(loops, &markers).unwrap(),
+		   Some((4410, 88200)));
+	// forward-backward plays forward
+	let loops = parse_inst(&inst((2, 1, 2), (0, 0, 0))).unwrap();
+	assert_eq!(sustain_loop(loops, &markers).unwrap(),
+		   Some((4410, 88200)));
+    }
+
+    #[test]
+    fn no_loop() {
+	let markers = parse_mark(&mark(&[(1, 4410, "start"),
+					  (2, 88200, "end")])).unwrap();
+	// only a release loop
+	let loops = parse_inst(&inst((0, 0, 0), (1, 1, 2))).unwrap();
+	assert_eq!(sustain_loop(loops, &markers).unwrap(), None);
+	assert!(parse_inst(&[0; 12]).is_err());
+    }
+
+    #[test]
+    fn broken_release_loop() {
+	// only the sustain loop gets played, so only it has to make sense
+	let markers = parse_mark(&mark(&[(1, 4410, "start"),
+					  (2, 88200, "end")])).unwrap();
+	let loops = parse_inst(&inst((1, 1, 2), (1, 1, 9))).unwrap();
+	assert_eq!(sustain_loop(loops, &markers).unwrap(),
+		   Some((4410, 88200)));
+    }
+
+    #[test]
+    fn bad_loops() {
+	let markers = parse_mark(&mark(&[(1, 4410, "start"),
+					  (2, 88200, "end")])).unwrap();
+	let backwards = parse_inst(&inst((1, 2, 1), (0, 0, 0))).unwrap();
+	assert!(sustain_loop(backwards, &markers).is_err());
+	let missing = parse_inst(&inst((1, 1, 3), (0, 0, 0))).unwrap();
+	assert!(sustain_loop(missing, &markers).is_err());
+    }
+}
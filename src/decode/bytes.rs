@@ -0,0 +1,17 @@
+//! Reading integers out of file headers, which come in both byte orders.
+
+pub fn read_u16_le(buf: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([buf[at], buf[at+1]])
+}
+
+pub fn read_u32_le(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at+1], buf[at+2], buf[at+3]])
+}
+
+pub fn read_u16_be(buf: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([buf[at], buf[at+1]])
+}
+
+pub fn read_u32_be(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([buf[at], buf[at+1], buf[at+2], buf[at+3]])
+}
@@ -0,0 +1,131 @@
+//! FLAC, native or in Ogg, via Claxon.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+};
+
+use anyhow::anyhow;
+use claxon::FlacReader;
+use log::trace;
+use ogg::PacketReader;
+
+use super::{LoopPoints, Source, Stream};
+
+/// How the first packet of an Ogg FLAC stream begins: 0x7F, "FLAC", the
+/// mapping version, and the header packet count. After that comes a native
+/// FLAC stream header ("fLaC" and the STREAMINFO block).
+const OGG_FLAC_MAPPING_HEADER_LEN: usize = 9;
+
+struct FlacSource<R: Read> {
+    reader: FlacReader<R>,
+    buffer: Vec<i32>,
+    channel_count: usize,
+    /// multiply by this to get samples between -1 and 1
+    scale: f32,
+}
+
+/// Unwraps an Ogg FLAC stream back into a native FLAC stream. The header
+/// packets are the native metadata blocks, and every audio packet is one
+/// native frame, so all we have to do is strip the mapping header off of the
+/// first packet and glue the rest together.
+struct OggFlacReader {
+    rdr: PacketReader<File>,
+    stream_serial: Option<u32>,
+    packet: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for OggFlacReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+	while self.pos >= self.packet.len() {
+	    let packet = match self.rdr.read_packet()
+		.map_err(io::Error::other)? {
+		    Some(x) => x,
+		    None => return Ok(0),
+		};
+	    match self.stream_serial {
+		None => {
+		    if packet.data.len() < OGG_FLAC_MAPPING_HEADER_LEN
+			|| !packet.data.starts_with(b"\x7FFLAC") {
+			    return Err(io::Error::new(io::ErrorKind::InvalidData,
+						      "not an Ogg FLAC stream"))
+			}
+		    self.stream_serial = Some(packet.stream_serial());
+		    self.pos = OGG_FLAC_MAPPING_HEADER_LEN;
+		},
+		Some(x) if x != packet.stream_serial() => continue,
+		Some(_) => self.pos = 0,
+	    }
+	    self.packet = packet.data;
+	}
+	let amount = buf.len().min(self.packet.len() - self.pos);
+	buf[..amount]
+	    .copy_from_slice(&self.packet[self.pos .. self.pos + amount]);
+	self.pos += amount;
+	Ok(amount)
+    }
+}
+
+fn open_reader<R: Read + Send + 'static>(reader: FlacReader<R>)
+					  -> anyhow::Result<Stream> {
+    let streaminfo = reader.streaminfo();
+    let channel_count = match streaminfo.channels {
+	0 => return Err(anyhow!("stream says it has no channels")),
+	x => x,
+    };
+    let sample_rate = match streaminfo.sample_rate {
+	0 => return Err(anyhow!("stream says it's 0Hz, that unpossible")),
+	x => x,
+    };
+    if let Some(vendor) = reader.vendor() {
+	trace!("Vendor: {}", vendor);
+    }
+    let tags: Vec<(&str, &str)> = reader.tags().collect();
+    let loop_points = LoopPoints::from_comments(&tags, sample_rate)?;
+    // FLAC's channel order for more than two channels is already the WAVE
+    // order, so there's no need to shuffle anything
+    let scale = 1.0 / (1u32 << (streaminfo.bits_per_sample - 1)) as f32;
+    Ok(Stream {
+	sample_rate, channel_count, loop_points,
+	length: streaminfo.samples.map(|x| x as usize),
+	source: Box::new(FlacSource {
+	    reader, buffer: Vec::new(),
+	    channel_count: channel_count as usize, scale,
+	}),
+    })
+}
+
+pub fn open_native(file: File) -> anyhow::Result<Stream> {
+    open_reader(FlacReader::new(file)?)
+}
+
+pub fn open_ogg(file: File) -> anyhow::Result<Stream> {
+    open_reader(FlacReader::new(OggFlacReader {
+	rdr: PacketReader::new(file),
+	stream_serial: None,
+	packet: Vec::new(),
+	pos: 0,
+    })?)
+}
+
+impl<R: Read + Send> Source for FlacSource<R> {
+    fn next_packet(&mut self) -> anyhow::Result<Option<Vec<f32>>> {
+	let buffer = std::mem::take(&mut self.buffer);
+	let block = match self.reader.blocks().read_next_or_eof(buffer)? {
+	    Some(x) => x,
+	    None => return Ok(None),
+	};
+	let frame_count = block.duration() as usize;
+	let mut out_buf = vec![0.0; frame_count * self.channel_count];
+	for n in 0 .. self.channel_count {
+	    for (o, &i) in out_buf[n..].iter_mut()
+		.step_by(self.channel_count)
+		.zip(block.channel(n as u32).iter()) {
+		    *o = i as f32 * self.scale;
+		}
+	}
+	self.buffer = block.into_buffer();
+	Ok(Some(out_buf))
+    }
+}
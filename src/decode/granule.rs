@@ -0,0 +1,134 @@
+//! Finding out how long an Ogg stream is without decoding it, by reading the
+//! granule position of its last page.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use log::trace;
+use ogg::PacketReader;
+
+/// The biggest an Ogg page can be: a 27-byte header, 255 lacing values, and
+/// 255 segments of 255 bytes each. The last page has to start somewhere in
+/// this many bytes from the end.
+const MAX_PAGE_LEN: u64 = 27 + 255 + 255 * 255;
+
+/// Ogg uses this granule position to mean "no packet ends on this page".
+pub(crate) const NO_GRANULE: u64 = u64::MAX;
+
+/// Returns the granule position of the last complete page in `buf`, along
+/// with the serial number of the stream it belongs to.
+fn find_last_page(buf: &[u8]) -> Option<(u32, u64)> {
+    let mut ret = None;
+    let mut at = 0;
+    while let Some(n) = buf[at..].windows(4).position(|x| x == b"OggS") {
+	let page = &buf[at + n ..];
+	at += n + 1;
+	// version 0 only, and the whole page has to be here (which also weeds
+	// out most "OggS" that turn up by chance in the middle of a page)
+	if page.len() < 27 || page[4] != 0 { continue }
+	let segment_count = page[26] as usize;
+	let lacing = match page.get(27 .. 27 + segment_count) {
+	    Some(x) => x,
+	    None => continue,
+	};
+	let page_len = 27 + segment_count
+	    + lacing.iter().map(|&x| x as usize).sum::<usize>();
+	if page.len() < page_len { continue }
+	let granule = u64::from_le_bytes(page[6..14].try_into().unwrap());
+	let serial = u32::from_le_bytes(page[14..18].try_into().unwrap());
+	if granule != NO_GRANULE { ret = Some((serial, granule)) }
+    }
+    ret
+}
+
+/// Peeks at the end of the file to find the granule position where stream
+/// `serial` ends, then puts the reader back where it was. Should only be
+/// called between pages, e.g. right after the headers are read.
+///
+/// Returns `None` if the last page belongs to some other stream, such as the
+/// next link in a chain, since then it doesn't tell us anything useful.
+pub fn find_end<R: Read + Seek>(rdr: PacketReader<R>, serial: u32)
+		-> anyhow::Result<(PacketReader<R>, Option<u64>)> {
+    let mut file = rdr.into_inner();
+    let pos = file.stream_position()?;
+    let file_len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(file_len.saturating_sub(MAX_PAGE_LEN)))?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    let ret = match find_last_page(&buf) {
+	Some((x, granule)) if x == serial => {
+	    trace!("Stream ends at granule position {}", granule);
+	    Some(granule)
+	},
+	Some(_) => {
+	    trace!("Last page belongs to another stream, length unknown");
+	    None
+	},
+	None => None,
+    };
+    // a fresh reader doesn't like starting mid-stream unless it's been told
+    // that it's seeked there
+    let mut rdr = PacketReader::new(file);
+    rdr.seek_bytes(SeekFrom::Start(pos))?;
+    Ok((rdr, ret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An Ogg page with one `len`-byte packet on it.
+    fn page(serial: u32, granule: u64, len: u8) -> Vec<u8> {
+	let mut ret = b"OggS\0\0".to_vec();
+	ret.extend_from_slice(&granule.to_le_bytes());
+	ret.extend_from_slice(&serial.to_le_bytes());
+	// sequence number and CRC, which we don't look at
+	ret.extend_from_slice(&[0; 8]);
+	ret.extend_from_slice(&[1, len]);
+	ret.extend(std::iter::repeat(0x55).take(len as usize));
+	ret
+    }
+
+    #[test]
+    fn last_page_wins() {
+	// starting partway into a page, as we would be after seeking
+	let mut buf = page(1, 1000, 40)[30..].to_vec();
+	buf.extend(page(1, 2000, 40));
+	buf.extend(page(1, 3000, 40));
+	assert_eq!(find_last_page(&buf), Some((1, 3000)));
+    }
+
+    #[test]
+    fn serial() {
+	let mut buf = page(1, 1000, 40);
+	buf.extend(page(2, 50, 40));
+	assert_eq!(find_last_page(&buf), Some((2, 50)));
+    }
+
+    #[test]
+    fn truncated_page() {
+	let mut buf = page(1, 1000, 40);
+	let last = page(1, 2000, 40);
+	buf.extend_from_slice(&last[.. last.len() - 1]);
+	assert_eq!(find_last_page(&buf), Some((1, 1000)));
+    }
+
+    #[test]
+    fn no_granule() {
+	let mut buf = page(1, 1000, 40);
+	buf.extend(page(1, NO_GRANULE, 40));
+	assert_eq!(find_last_page(&buf), Some((1, 1000)));
+	assert_eq!(find_last_page(&page(1, NO_GRANULE, 40)), None);
+    }
+
+    #[test]
+    fn capture_pattern_in_data() {
+	// "OggS" turning up where it isn't the start of a page we can read
+	let mut buf = page(1, 1000, 40);
+	let mut other_version = page(1, 2000, 40);
+	other_version[4] = 1;
+	buf.extend(other_version);
+	buf.extend(b"OggS and then not much");
+	assert_eq!(find_last_page(&buf), Some((1, 1000)));
+	assert_eq!(find_last_page(b"junk"), None);
+    }
+}
@@ -0,0 +1,393 @@
+//! MP3, via minimp3, with loop tags from ID3v2 `TXXX` frames and gapless
+//! trimming from the LAME header.
+
+use std::{
+    fs::File,
+    io::{Cursor, Read},
+};
+
+use anyhow::anyhow;
+use log::trace;
+use minimp3::{Decoder, Error as Mp3Error};
+
+use super::{bytes::read_u32_be, LoopPoints, Source, Stream};
+
+/// Every MP3 decoder adds this many samples of delay, on top of whatever
+/// delay the encoder added. The LAME header doesn't count it, so we have to.
+const DECODER_DELAY: usize = 528 + 1;
+
+/// How the encoders that write the LAME extension start their version
+/// strings. Anything else after a Xing tag isn't a LAME extension, and its
+/// "delay and padding" would be nonsense.
+const LAME_SIGNATURES: [&[u8; 4]; 4] = [b"LAME", b"Lavc", b"Lavf", b"L3.9"];
+
+struct Mp3Source {
+    decoder: Decoder<Cursor<Vec<u8>>>,
+    channel_count: usize,
+    /// how many more decoded frames to throw away (encoder + decoder delay)
+    frames_to_skip: usize,
+    /// how many frames are left before the encoder padding, if we know
+    frames_left: Option<usize>,
+}
+
+/// What we need to know about an MPEG audio frame header.
+struct FrameHeader {
+    sample_rate: u32,
+    channel_count: u32,
+    /// the size of the whole frame, in bytes
+    frame_len: usize,
+    /// how many samples the frame decodes to
+    frame_samples: usize,
+    /// where the Xing/Info tag would be, relative to the frame start
+    xing_offset: usize,
+}
+
+/// A 28-bit integer spread across four bytes, seven bits each, as ID3v2 uses
+/// to avoid containing false MPEG sync patterns.
+fn read_syncsafe(buf: &[u8], at: usize) -> usize {
+    buf[at..at+4].iter().fold(0, |a, &x| (a << 7) | (x & 0x7F) as usize)
+}
+
+/// Returns the encoder delay and padding from a LAME extension, or `None` if
+/// there isn't one.
+fn parse_lame(lame: &[u8]) -> Option<(usize, usize)> {
+    if lame.len() < 24 { return None }
+    if !LAME_SIGNATURES.iter().any(|x| &lame[..4] == *x) {
+	trace!("no LAME extension, not trimming");
+	return None
+    }
+    let delay = ((lame[21] as usize) << 4) | (lame[22] as usize >> 4);
+    let padding = (((lame[22] & 0xF) as usize) << 8) | lame[23] as usize;
+    Some((delay, padding))
+}
+
+/// Parses a layer III frame header. Returns `None` if it isn't one.
+fn parse_frame_header(buf: &[u8]) -> Option<FrameHeader> {
+    const BITRATES_V1: [u32; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128,
+				    160, 192, 224, 256, 320];
+    const BITRATES_V2: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80,
+				    96, 112, 128, 144, 160];
+    const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+    if buf.len() < 4 { return None }
+    let header = read_u32_be(buf, 0);
+    if header & 0xFFE00000 != 0xFFE00000 { return None }
+    // 3 = MPEG-1, 2 = MPEG-2, 0 = MPEG-2.5
+    let version = (header >> 19) & 3;
+    let layer = (header >> 17) & 3;
+    let bitrate_index = ((header >> 12) & 15) as usize;
+    let sample_rate_index = ((header >> 10) & 3) as usize;
+    let padding = ((header >> 9) & 1) as usize;
+    let mono = (header >> 6) & 3 == 3;
+    // layer III only, no reserved values, no "free format"
+    if version == 1 || layer != 1 || bitrate_index == 0 || bitrate_index == 15
+	|| sample_rate_index == 3 { return None }
+    let is_mpeg1 = version == 3;
+    let bitrate = if is_mpeg1 { BITRATES_V1[bitrate_index] }
+    else { BITRATES_V2[bitrate_index] } as usize * 1000;
+    let sample_rate = SAMPLE_RATES[sample_rate_index] >> match version {
+	3 => 0,
+	2 => 1,
+	_ => 2,
+    };
+    let frame_samples = if is_mpeg1 { 1152 } else { 576 };
+    let frame_len = frame_samples / 8 * bitrate / sample_rate as usize
+	+ padding;
+    // the Xing tag comes after the side info
+    let side_info_len = match (is_mpeg1, mono) {
+	(true, false) => 32,
+	(true, true) | (false, false) => 17,
+	(false, true) => 9,
+    };
+    Some(FrameHeader {
+	sample_rate,
+	channel_count: if mono { 1 } else { 2 },
+	frame_len, frame_samples,
+	xing_offset: 4 + side_info_len,
+    })
+}
+
+/// Reverses ID3v2 unsynchronization: every 0xFF 0x00 becomes 0xFF.
+fn resynchronize(buf: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(buf.len());
+    let mut last = 0;
+    for &x in buf.iter() {
+	if !(last == 0xFF && x == 0x00) { ret.push(x) }
+	last = x;
+    }
+    ret
+}
+
+/// Decodes an ID3v2 text field, according to its encoding byte.
+fn decode_text(encoding: u8, buf: &[u8]) -> String {
+    match encoding {
+	// UTF-16, with or without BOM
+	1 | 2 => {
+	    let big_endian = match buf {
+		[0xFF, 0xFE, ..] => false,
+		[0xFE, 0xFF, ..] => true,
+		_ => encoding == 2,
+	    };
+	    let units: Vec<u16> = buf.chunks_exact(2)
+		.map(|x| if big_endian { u16::from_be_bytes([x[0], x[1]]) }
+		     else { u16::from_le_bytes([x[0], x[1]]) })
+		.skip_while(|&x| x == 0xFEFF)
+		.take_while(|&x| x != 0)
+		.collect();
+	    String::from_utf16_lossy(&units)
+	},
+	// ISO-8859-1, which maps directly onto the first 256 code points
+	0 => buf.iter().take_while(|&&x| x != 0).map(|&x| x as char).collect(),
+	// UTF-8
+	_ => {
+	    let end = buf.iter().position(|&x| x == 0).unwrap_or(buf.len());
+	    String::from_utf8_lossy(&buf[..end]).into_owned()
+	},
+    }
+}
+
+/// Splits a `TXXX` frame into its description and value.
+fn parse_txxx(frame: &[u8]) -> Option<(String, String)> {
+    let (&encoding, rest) = frame.split_first()?;
+    // find the terminator after the description
+    let value_at = if encoding == 1 || encoding == 2 {
+	rest.chunks_exact(2).position(|x| x == [0, 0])? * 2 + 2
+    } else {
+	rest.iter().position(|&x| x == 0)? + 1
+    };
+    Some((decode_text(encoding, &rest[..value_at]),
+	  decode_text(encoding, &rest[value_at..])))
+}
+
+/// Returns every `TXXX` frame in an ID3v2 tag (minus its 10-byte header).
+fn parse_id3v2(major_version: u8, flags: u8, tag: &[u8])
+	       -> Vec<(String, String)> {
+    let tag = if flags & 0x80 != 0 { resynchronize(tag) }
+    else { tag.to_vec() };
+    let mut pos = 0;
+    if flags & 0x40 != 0 && tag.len() >= 4 {
+	// skip the extended header
+	pos = if major_version >= 4 { read_syncsafe(&tag, 0) }
+	else { read_u32_be(&tag, 0) as usize + 4 };
+    }
+    let (id_len, header_len) = if major_version == 2 { (3, 6) }
+    else { (4, 10) };
+    let mut ret = Vec::new();
+    while tag.len().saturating_sub(pos) >= header_len {
+	let id = &tag[pos .. pos + id_len];
+	if id[0] == 0 { break } // padding
+	let len = match major_version {
+	    2 => u32::from_be_bytes([0, tag[pos+3], tag[pos+4], tag[pos+5]])
+		as usize,
+	    3 => read_u32_be(&tag, pos + 4) as usize,
+	    _ => read_syncsafe(&tag, pos + 4),
+	};
+	pos += header_len;
+	if tag.len() - pos < len { break }
+	let frame = &tag[pos .. pos + len];
+	pos += len;
+	if id == b"TXXX" || id == b"TXX" {
+	    match parse_txxx(frame) {
+		Some(x) => ret.push(x),
+		None => trace!("malformed TXXX frame"),
+	    }
+	}
+    }
+    ret
+}
+
+pub fn open(mut file: File) -> anyhow::Result<Stream> {
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    let mut pos = 0;
+    let mut comments = Vec::new();
+    // there may be more than one ID3v2 tag, one after the other
+    while buf.len() - pos >= 10 && &buf[pos .. pos + 3] == b"ID3" {
+	let major_version = buf[pos + 3];
+	let flags = buf[pos + 5];
+	let len = read_syncsafe(&buf, pos + 6);
+	trace!("ID3v2.{} tag, {} bytes", major_version, len);
+	let tag_end = (pos + 10 + len).min(buf.len());
+	comments.extend(parse_id3v2(major_version, flags,
+				    &buf[pos + 10 .. tag_end]));
+	// a footer, if present, is another 10 bytes
+	pos = tag_end + if flags & 0x10 != 0 { 10 } else { 0 };
+	pos = pos.min(buf.len());
+    }
+    // find the first frame
+    while pos < buf.len() && parse_frame_header(&buf[pos..]).is_none() {
+	pos += 1;
+    }
+    let first_frame = match parse_frame_header(&buf[pos..]) {
+	Some(x) => x,
+	None => return Err(anyhow!("no MP3 frames found")),
+    };
+    let sample_rate = first_frame.sample_rate;
+    let channel_count = first_frame.channel_count;
+    let mut frames_to_skip = 0;
+    let mut frames_left = None;
+    // a Xing/Info tag lives in a frame of silence at the very beginning
+    let xing_at = pos + first_frame.xing_offset;
+    if buf.len() >= xing_at + 8
+	&& (&buf[xing_at .. xing_at + 4] == b"Xing"
+	    || &buf[xing_at .. xing_at + 4] == b"Info") {
+	    let xing_flags = read_u32_be(&buf, xing_at + 4);
+	    let mut at = xing_at + 8;
+	    let mut frame_count = None;
+	    if xing_flags & 1 != 0 && buf.len() >= at + 4 {
+		frame_count = Some(read_u32_be(&buf, at) as usize);
+	    }
+	    // skip the frame count, byte count, table of contents, and
+	    // quality, whichever are present
+	    for (flag, len) in [(1, 4), (2, 4), (4, 100), (8, 4)] {
+		if xing_flags & flag != 0 { at += len }
+	    }
+	    // the LAME extension, if present, has the encoder delay and
+	    // padding at a fixed offset
+	    if let (Some(frame_count), Some((delay, padding))) =
+		(frame_count, parse_lame(&buf[at.min(buf.len())..])) {
+		    trace!("LAME header: {} frames, delay {}, padding {}",
+			   frame_count, delay, padding);
+		    frames_to_skip = delay + DECODER_DELAY;
+		    frames_left = Some((frame_count * first_frame.frame_samples)
+				       .saturating_sub(delay + padding));
+		}
+	    // the tag frame isn't audio, don't decode it
+	    pos += first_frame.frame_len;
+	}
+    let loop_points = LoopPoints::from_comments(&comments, sample_rate)?;
+    buf.drain(..pos);
+    Ok(Stream {
+	sample_rate, channel_count, loop_points,
+	length: frames_left,
+	source: Box::new(Mp3Source {
+	    decoder: Decoder::new(Cursor::new(buf)),
+	    channel_count: channel_count as usize,
+	    frames_to_skip, frames_left,
+	}),
+    })
+}
+
+impl Source for Mp3Source {
+    fn next_packet(&mut self) -> anyhow::Result<Option<Vec<f32>>> {
+	if self.frames_left == Some(0) { return Ok(None) }
+	let frame = match self.decoder.next_frame() {
+	    Ok(x) => x,
+	    Err(Mp3Error::Eof) => return Ok(None),
+	    Err(Mp3Error::SkippedData) => return Ok(Some(vec![])),
+	    Err(Mp3Error::InsufficientData) => return Ok(None),
+	    Err(Mp3Error::Io(x)) => return Err(x.into()),
+	};
+	if frame.channels != self.channel_count {
+	    return Err(anyhow!("channel count changed mid-stream"))
+	}
+	let mut out_buf: Vec<f32> = frame.data.iter()
+	    .map(|&x| x as f32 / 32768.0).collect();
+	if self.frames_to_skip > 0 {
+	    let skipped = self.frames_to_skip
+		.min(out_buf.len() / self.channel_count);
+	    out_buf.drain(.. skipped * self.channel_count);
+	    self.frames_to_skip -= skipped;
+	}
+	if let Some(frames_left) = self.frames_left.as_mut() {
+	    let frame_count = (out_buf.len() / self.channel_count)
+		.min(*frames_left);
+	    out_buf.truncate(frame_count * self.channel_count);
+	    *frames_left -= frame_count;
+	}
+	Ok(Some(out_buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A LAME extension starting with `version`, with the given encoder
+    /// delay and padding.
+    fn lame(version: &[u8; 9], delay: usize, padding: usize) -> Vec<u8> {
+	let mut ret = vec![0u8; 36];
+	ret[..9].copy_from_slice(version);
+	ret[21] = (delay >> 4) as u8;
+	ret[22] = ((delay & 0xF) << 4) as u8 | (padding >> 8) as u8;
+	ret[23] = padding as u8;
+	ret
+    }
+
+    /// An ID3v2.3 or 2.4 frame.
+    fn frame(major_version: u8, id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+	let mut ret = id.to_vec();
+	let len = body.len() as u32;
+	if major_version >= 4 {
+	    ret.extend((0 .. 4).rev().map(|n| (len >> (n * 7)) as u8 & 0x7F));
+	}
+	else { ret.extend_from_slice(&len.to_be_bytes()) }
+	ret.extend_from_slice(&[0, 0]);
+	ret.extend_from_slice(body);
+	ret
+    }
+
+    #[test]
+    fn lame_delay_and_padding() {
+	assert_eq!(parse_lame(&lame(b"LAME3.100", 576, 1234)),
+		   Some((576, 1234)));
+	assert_eq!(parse_lame(&lame(b"Lavf58.29", 1105, 0)), Some((1105, 0)));
+    }
+
+    #[test]
+    fn not_lame() {
+	// some other encoder's idea of what goes after the Xing tag
+	assert_eq!(parse_lame(&lame(b"GOGO\0\0\0\0\0", 576, 1234)), None);
+	assert_eq!(parse_lame(&[0; 36]), None);
+	assert_eq!(parse_lame(b"LAME3.100"), None);
+    }
+
+    #[test]
+    fn txxx_v3() {
+	let mut tag = frame(3, b"TIT2", b"\0Title");
+	tag.extend(frame(3, b"TXXX", b"\0LOOP_START\x0044100"));
+	tag.extend(frame(3, b"TXXX", b"\0LOOP_END\x00\xB1441000"));
+	tag.extend_from_slice(&[0; 16]); // padding
+	assert_eq!(parse_id3v2(3, 0, &tag), vec![
+	    ("LOOP_START".to_owned(), "44100".to_owned()),
+	    ("LOOP_END".to_owned(), "\u{B1}441000".to_owned()),
+	]);
+    }
+
+    #[test]
+    fn txxx_v4_utf16() {
+	let mut body = vec![1];
+	for text in ["LOOPSTART", "88200"] {
+	    body.extend_from_slice(&[0xFF, 0xFE]);
+	    body.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+	    body.extend_from_slice(&[0, 0]);
+	}
+	// big enough that the syncsafe length matters
+	body.resize(200, 0);
+	let tag = frame(4, b"TXXX", &body);
+	assert_eq!(parse_id3v2(4, 0, &tag), vec![
+	    ("LOOPSTART".to_owned(), "88200".to_owned()),
+	]);
+    }
+
+    #[test]
+    fn txx_v2() {
+	let mut tag = b"TXX\0\0\x0D".to_vec();
+	tag.extend_from_slice(b"\x03LOOP_END\x00123");
+	assert_eq!(parse_id3v2(2, 0, &tag), vec![
+	    ("LOOP_END".to_owned(), "123".to_owned()),
+	]);
+    }
+
+    #[test]
+    fn unsynchronized() {
+	let tag = frame(3, b"TXXX", b"\0LOOP_START\0\xFF");
+	let mut unsynced = Vec::new();
+	for &x in tag.iter() {
+	    unsynced.push(x);
+	    if x == 0xFF { unsynced.push(0) }
+	}
+	assert_eq!(parse_id3v2(3, 0x80, &unsynced), vec![
+	    ("LOOP_START".to_owned(), "\u{FF}".to_owned()),
+	]);
+    }
+}
@@ -0,0 +1,566 @@
+//! Nintendo's streamed audio containers: BRSTM (Wii), BCSTM (3DS), and BFSTM
+//! (Wii U and Switch). All three hold PCM or DSP-ADPCM audio in interleaved
+//! blocks, and have a loop flag, a loop start, and a sample count in the
+//! header.
+
+use std::{
+    fs::File,
+    io::Read,
+};
+
+use anyhow::anyhow;
+use log::trace;
+
+use super::{LoopPoints, Source, Stream};
+
+const CODEC_PCM8: u8 = 0;
+const CODEC_PCM16: u8 = 1;
+const CODEC_DSP_ADPCM: u8 = 2;
+
+/// Each DSP-ADPCM frame is a header byte followed by 14 nybbles.
+const DSP_FRAME_BYTES: usize = 8;
+const DSP_FRAME_SAMPLES: usize = 14;
+
+/// Reads fields out of a header in whichever byte order the file's BOM says.
+struct Header {
+    buf: Vec<u8>,
+    big_endian: bool,
+}
+
+impl Header {
+    fn bytes(&self, at: usize, len: usize) -> anyhow::Result<&[u8]> {
+	self.buf.get(at .. at.saturating_add(len))
+	    .ok_or_else(|| anyhow!("header field at {:#X} is past the end of \
+				    the file", at))
+    }
+    fn u8(&self, at: usize) -> anyhow::Result<u8> {
+	Ok(self.bytes(at, 1)?[0])
+    }
+    fn u16(&self, at: usize) -> anyhow::Result<u16> {
+	let x = self.bytes(at, 2)?;
+	let x = [x[0], x[1]];
+	Ok(if self.big_endian { u16::from_be_bytes(x) }
+	   else { u16::from_le_bytes(x) })
+    }
+    fn i16(&self, at: usize) -> anyhow::Result<i16> {
+	Ok(self.u16(at)? as i16)
+    }
+    fn u32(&self, at: usize) -> anyhow::Result<u32> {
+	let x = self.bytes(at, 4)?;
+	let x = [x[0], x[1], x[2], x[3]];
+	Ok(if self.big_endian { u32::from_be_bytes(x) }
+	   else { u32::from_le_bytes(x) })
+    }
+    /// Reads a 32-bit offset and adds it to `base`.
+    fn offset(&self, at: usize, base: usize) -> anyhow::Result<usize> {
+	Ok(base.saturating_add(self.u32(at)? as usize))
+    }
+    fn expect_magic(&self, at: usize, magic: &[u8]) -> anyhow::Result<()> {
+	if self.bytes(at, magic.len())? != magic {
+	    return Err(anyhow!("expected {:?} block at {:#X}",
+			       String::from_utf8_lossy(magic), at))
+	}
+	Ok(())
+    }
+}
+
+/// The parts of the stream info that we care about. Positions are absolute
+/// file offsets, sizes are per channel.
+#[derive(Debug)]
+struct StreamInfo {
+    codec: u8,
+    loop_flag: bool,
+    channel_count: usize,
+    sample_rate: u32,
+    loop_start: u32,
+    sample_count: u32,
+    data_offset: usize,
+    block_count: usize,
+    block_size: usize,
+    block_samples: usize,
+    last_block_samples: usize,
+    last_block_padded_size: usize,
+}
+
+#[derive(Debug,Clone)]
+struct DspChannel {
+    coefs: [i16; 16],
+    hist1: i16,
+    hist2: i16,
+}
+
+impl DspChannel {
+    /// `at` points to the ADPCM info: 16 coefficients, then (in the newer
+    /// formats) the initial predictor/scale and history, or (in BRSTM) a
+    /// gain field and then those.
+    fn read(header: &Header, at: usize, has_gain: bool)
+	    -> anyhow::Result<DspChannel> {
+	let mut coefs = [0; 16];
+	for (n, coef) in coefs.iter_mut().enumerate() {
+	    *coef = header.i16(at + n * 2)?;
+	}
+	// skip gain (if present) and initial predictor/scale
+	let at = at + 0x22 + if has_gain { 2 } else { 0 };
+	Ok(DspChannel {
+	    coefs,
+	    hist1: header.i16(at)?,
+	    hist2: header.i16(at + 2)?,
+	})
+    }
+    /// Decodes `sample_count` samples from `data`, writing every
+    /// `stride`th float of `out`.
+    fn decode(&mut self, data: &[u8], sample_count: usize,
+	      out: &mut [f32], stride: usize) {
+	let mut out = out.iter_mut().step_by(stride);
+	for frame in data.chunks(DSP_FRAME_BYTES)
+	    .take(sample_count.div_ceil(DSP_FRAME_SAMPLES)) {
+		let scale = 1i32 << (frame[0] & 0xF);
+		let coef_index = ((frame[0] >> 4) & 7) as usize * 2;
+		let coef1 = self.coefs[coef_index] as i32;
+		let coef2 = self.coefs[coef_index + 1] as i32;
+		for &byte in frame[1..].iter() {
+		    for nybble in [byte >> 4, byte & 0xF] {
+			let o = match out.next() {
+			    Some(x) => x,
+			    None => return,
+			};
+			// sign-extend the nybble
+			let nybble = ((nybble as i8) << 4 >> 4) as i32;
+			let sample = ((nybble * scale) << 11) + 1024
+			    + coef1 * self.hist1 as i32
+			    + coef2 * self.hist2 as i32;
+			let sample = (sample >> 11)
+			    .clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+			self.hist2 = self.hist1;
+			self.hist1 = sample;
+			*o = sample as f32 / 32768.0;
+		    }
+		}
+	    }
+    }
+}
+
+struct NintendoSource {
+    header: Header,
+    info: StreamInfo,
+    dsp_channels: Vec<DspChannel>,
+    next_block: usize,
+    frames_left: usize,
+}
+
+/// Reads the BRSTM headers.
+fn parse_rstm(header: &Header)
+	      -> anyhow::Result<(StreamInfo, Vec<DspChannel>)> {
+    let head = header.u32(0x10)? as usize;
+    header.expect_magic(head, b"HEAD")?;
+    // offsets within HEAD are relative to just after its chunk header
+    let base = head + 8;
+    let info_at = header.offset(base + 0x04, base)?;
+    let channels_at = header.offset(base + 0x14, base)?;
+    let info = StreamInfo {
+	codec: header.u8(info_at)?,
+	loop_flag: header.u8(info_at + 1)? != 0,
+	channel_count: header.u8(info_at + 2)? as usize,
+	sample_rate: header.u16(info_at + 4)? as u32,
+	loop_start: header.u32(info_at + 8)?,
+	sample_count: header.u32(info_at + 0xC)?,
+	data_offset: header.u32(info_at + 0x10)? as usize,
+	block_count: header.u32(info_at + 0x14)? as usize,
+	block_size: header.u32(info_at + 0x18)? as usize,
+	block_samples: header.u32(info_at + 0x1C)? as usize,
+	last_block_samples: header.u32(info_at + 0x24)? as usize,
+	last_block_padded_size: header.u32(info_at + 0x28)? as usize,
+    };
+    let mut dsp_channels = Vec::new();
+    if info.codec == CODEC_DSP_ADPCM {
+	let table_count = header.u8(channels_at)? as usize;
+	if table_count < info.channel_count {
+	    return Err(anyhow!("BRSTM has fewer channel info entries than \
+				channels"))
+	}
+	for n in 0 .. info.channel_count {
+	    let channel_at = header.offset(channels_at + 8 + n * 8, base)?;
+	    let adpcm_at = header.offset(channel_at + 4, base)?;
+	    dsp_channels.push(DspChannel::read(header, adpcm_at, true)?);
+	}
+    }
+    Ok((info, dsp_channels))
+}
+
+/// Reads the BCSTM/BFSTM headers, which are laid out identically.
+fn parse_cstm_fstm(header: &Header)
+		   -> anyhow::Result<(StreamInfo, Vec<DspChannel>)> {
+    let section_count = header.u16(0x10)? as usize;
+    let mut info = None;
+    let mut data = None;
+    for n in 0 .. section_count {
+	let at = 0x14 + n * 12;
+	match header.u16(at)? {
+	    0x4000 => info = Some(header.u32(at + 4)? as usize),
+	    0x4002 => data = Some(header.u32(at + 4)? as usize),
+	    _ => (),
+	}
+    }
+    let (info, data) = match (info, data) {
+	(Some(info), Some(data)) => (info, data),
+	_ => return Err(anyhow!("stream is missing its INFO or DATA block")),
+    };
+    header.expect_magic(info, b"INFO")?;
+    header.expect_magic(data, b"DATA")?;
+    // offsets within INFO are relative to just after its block header
+    let base = info + 8;
+    let info_at = header.offset(base + 0x04, base)?;
+    let channels_at = header.offset(base + 0x14, base)?;
+    let info = StreamInfo {
+	codec: header.u8(info_at)?,
+	loop_flag: header.u8(info_at + 1)? != 0,
+	channel_count: header.u8(info_at + 2)? as usize,
+	sample_rate: header.u32(info_at + 4)?,
+	loop_start: header.u32(info_at + 8)?,
+	sample_count: header.u32(info_at + 0xC)?,
+	block_count: header.u32(info_at + 0x10)? as usize,
+	block_size: header.u32(info_at + 0x14)? as usize,
+	block_samples: header.u32(info_at + 0x18)? as usize,
+	last_block_samples: header.u32(info_at + 0x20)? as usize,
+	last_block_padded_size: header.u32(info_at + 0x24)? as usize,
+	data_offset: header.offset(info_at + 0x34, data + 8)?,
+    };
+    let mut dsp_channels = Vec::new();
+    if info.codec == CODEC_DSP_ADPCM {
+	let table_count = header.u32(channels_at)? as usize;
+	if table_count < info.channel_count {
+	    return Err(anyhow!("stream has fewer channel info entries than \
+				channels"))
+	}
+	for n in 0 .. info.channel_count {
+	    let channel_at = header.offset(channels_at + 8 + n * 8,
+					   channels_at)?;
+	    let adpcm_at = header.offset(channel_at + 4, channel_at)?;
+	    dsp_channels.push(DspChannel::read(header, adpcm_at, false)?);
+	}
+    }
+    Ok((info, dsp_channels))
+}
+
+pub fn open(mut file: File) -> anyhow::Result<Stream> {
+    // these files are ADPCM, and usually small; just slurp the whole thing
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    if buf.len() < 0x40 {
+	return Err(anyhow!("file is too short to be a Nintendo stream"))
+    }
+    let big_endian = match [buf[4], buf[5]] {
+	[0xFE, 0xFF] => true,
+	[0xFF, 0xFE] => false,
+	_ => return Err(anyhow!("Nintendo stream has a bad byte order mark")),
+    };
+    let header = Header { buf, big_endian };
+    let (info, dsp_channels) = match header.bytes(0, 4)? {
+	b"RSTM" => parse_rstm(&header)?,
+	b"CSTM" | b"FSTM" => parse_cstm_fstm(&header)?,
+	_ => return Err(anyhow!("not a BRSTM, BCSTM, or BFSTM file")),
+    };
+    trace!("{:?}", info);
+    match info.codec {
+	CODEC_PCM8 | CODEC_PCM16 | CODEC_DSP_ADPCM => (),
+	x => return Err(anyhow!("unhandled Nintendo stream codec: {}", x)),
+    }
+    if info.channel_count == 0 {
+	return Err(anyhow!("stream says it has no channels"))
+    }
+    if info.sample_rate == 0 {
+	return Err(anyhow!("stream says it's 0Hz, that unpossible"))
+    }
+    let loop_points = if info.loop_flag {
+	if info.loop_start >= info.sample_count {
+	    return Err(anyhow!("loop start ({}) is not before the end of the \
+				stream ({})", info.loop_start,
+			       info.sample_count))
+	}
+	// the loop ends where the stream ends
+	LoopPoints {
+	    left: info.loop_start as usize,
+	    right: info.sample_count as usize,
+	    mix: false,
+	    crossfade: None,
+	}
+    }
+    else {
+	trace!("loop flag not set, looping the whole stream");
+	LoopPoints { left: 0, right: usize::MAX, mix: false,
+		     crossfade: None }
+    };
+    Ok(Stream {
+	sample_rate: info.sample_rate,
+	channel_count: info.channel_count as u32,
+	loop_points,
+	length: Some(info.sample_count as usize),
+	source: Box::new(NintendoSource {
+	    header, dsp_channels, next_block: 0,
+	    frames_left: info.sample_count as usize,
+	    info,
+	}),
+    })
+}
+
+impl Source for NintendoSource {
+    fn next_packet(&mut self) -> anyhow::Result<Option<Vec<f32>>> {
+	let info = &self.info;
+	if self.next_block >= info.block_count || self.frames_left == 0 {
+	    return Ok(None)
+	}
+	let is_last = self.next_block == info.block_count - 1;
+	// every block before the last has the same size, and each block has
+	// each channel's data one after the other
+	let block_at = info.data_offset + self.next_block * info.block_size
+	    * info.channel_count;
+	let (stride, sample_count) = if is_last {
+	    (info.last_block_padded_size, info.last_block_samples)
+	} else {
+	    (info.block_size, info.block_samples)
+	};
+	let sample_count = sample_count.min(self.frames_left);
+	let mut out_buf = vec![0.0; sample_count * info.channel_count];
+	for channel in 0 .. info.channel_count {
+	    // if the file got cut short, decode what's there and let the rest
+	    // be silence
+	    let data = self.header.buf.get(block_at + channel * stride ..)
+		.unwrap_or(&[]);
+	    let data = &data[.. stride.min(data.len())];
+	    let out = &mut out_buf[channel..];
+	    match info.codec {
+		CODEC_DSP_ADPCM => self.dsp_channels[channel]
+		    .decode(data, sample_count, out, info.channel_count),
+		CODEC_PCM16 => {
+		    for (o, i) in out.iter_mut().step_by(info.channel_count)
+			.zip(data.chunks_exact(2)) {
+			    let i = [i[0], i[1]];
+			    *o = if self.header.big_endian {
+				i16::from_be_bytes(i)
+			    } else {
+				i16::from_le_bytes(i)
+			    } as f32 / 32768.0;
+			}
+		},
+		CODEC_PCM8 => {
+		    for (o, &i) in out.iter_mut().step_by(info.channel_count)
+			.zip(data.iter()) {
+			    *o = i as i8 as f32 / 128.0;
+			}
+		},
+		_ => unreachable!(),
+	    }
+	}
+	self.next_block += 1;
+	self.frames_left -= sample_count;
+	Ok(Some(out_buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a header a field at a time.
+    struct Builder {
+	buf: Vec<u8>,
+	big_endian: bool,
+    }
+
+    impl Builder {
+	fn new(magic: &[u8; 4], big_endian: bool) -> Builder {
+	    let mut ret = Builder { buf: vec![0; 0x400], big_endian };
+	    ret.buf[..4].copy_from_slice(magic);
+	    ret.u16(4, 0xFEFF);
+	    ret
+	}
+	fn u8(&mut self, at: usize, x: u8) { self.buf[at] = x }
+	fn u16(&mut self, at: usize, x: u16) {
+	    let x = if self.big_endian { x.to_be_bytes() }
+	    else { x.to_le_bytes() };
+	    self.buf[at .. at + 2].copy_from_slice(&x);
+	}
+	fn u32(&mut self, at: usize, x: u32) {
+	    let x = if self.big_endian { x.to_be_bytes() }
+	    else { x.to_le_bytes() };
+	    self.buf[at .. at + 4].copy_from_slice(&x);
+	}
+	/// An offset to `to`, relative to `base`.
+	fn offset(&mut self, at: usize, base: usize, to: usize) {
+	    self.u32(at, (to - base) as u32);
+	}
+	/// ADPCM info for channel `n`, with or without BRSTM's gain field.
+	fn adpcm(&mut self, at: usize, n: i16, has_gain: bool) {
+	    for coef in 0 .. 16 {
+		self.u16(at + coef * 2, (n * 100 + coef as i16) as u16);
+	    }
+	    let at = at + 0x22 + if has_gain { 2 } else { 0 };
+	    self.u16(at, (n + 1) as u16);
+	    self.u16(at + 2, (-n - 2) as u16);
+	}
+	fn build(self) -> Header {
+	    Header { buf: self.buf, big_endian: self.big_endian }
+	}
+    }
+
+    fn check_dsp_channels(dsp_channels: &[DspChannel]) {
+	assert_eq!(dsp_channels.len(), 2);
+	for (n, channel) in dsp_channels.iter().enumerate() {
+	    let n = n as i16;
+	    assert_eq!(channel.coefs[0], n * 100);
+	    assert_eq!(channel.coefs[15], n * 100 + 15);
+	    assert_eq!((channel.hist1, channel.hist2), (n + 1, -n - 2));
+	}
+    }
+
+    #[test]
+    fn byte_order() {
+	let header = Header { buf: vec![0x12, 0x34, 0x56, 0x78],
+			      big_endian: true };
+	assert_eq!(header.u16(0).unwrap(), 0x1234);
+	assert_eq!(header.u32(0).unwrap(), 0x12345678);
+	let header = Header { buf: header.buf, big_endian: false };
+	assert_eq!(header.u16(0).unwrap(), 0x3412);
+	assert_eq!(header.u32(0).unwrap(), 0x78563412);
+	assert!(header.u32(1).is_err());
+    }
+
+    #[test]
+    fn brstm() {
+	let mut b = Builder::new(b"RSTM", true);
+	b.u32(0x10, 0x40);
+	b.buf[0x40 .. 0x44].copy_from_slice(b"HEAD");
+	let base = 0x48;
+	let (info_at, channels_at) = (0x80, 0xC0);
+	b.offset(base + 0x04, base, info_at);
+	b.offset(base + 0x14, base, channels_at);
+	b.u8(info_at, CODEC_DSP_ADPCM);
+	b.u8(info_at + 1, 1);
+	b.u8(info_at + 2, 2);
+	b.u16(info_at + 4, 32000);
+	b.u32(info_at + 8, 1000);
+	b.u32(info_at + 0xC, 50000);
+	b.u32(info_at + 0x10, 0x3E0);
+	b.u32(info_at + 0x14, 4);
+	b.u32(info_at + 0x18, 0x2000);
+	b.u32(info_at + 0x1C, 14336);
+	b.u32(info_at + 0x24, 6992);
+	b.u32(info_at + 0x28, 0xFA0);
+	b.u8(channels_at, 2);
+	for n in 0 .. 2 {
+	    let channel_at = 0x100 + n * 8;
+	    let adpcm_at = 0x200 + n * 0x30;
+	    b.offset(channels_at + 8 + n * 8, base, channel_at);
+	    b.offset(channel_at + 4, base, adpcm_at);
+	    b.adpcm(adpcm_at, n as i16, true);
+	}
+	let (info, dsp_channels) = parse_rstm(&b.build()).unwrap();
+	assert_eq!((info.codec, info.loop_flag, info.channel_count),
+		   (CODEC_DSP_ADPCM, true, 2));
+	assert_eq!((info.sample_rate, info.loop_start, info.sample_count),
+		   (32000, 1000, 50000));
+	assert_eq!((info.data_offset, info.block_count, info.block_size,
+		    info.block_samples), (0x3E0, 4, 0x2000, 14336));
+	assert_eq!((info.last_block_samples, info.last_block_padded_size),
+		   (6992, 0xFA0));
+	check_dsp_channels(&dsp_channels);
+    }
+
+    #[test]
+    fn bfstm() {
+	let mut b = Builder::new(b"FSTM", false);
+	b.u16(0x10, 2);
+	b.u16(0x14, 0x4000);
+	b.u32(0x18, 0x40);
+	b.u16(0x20, 0x4002);
+	b.u32(0x24, 0x380);
+	b.buf[0x40 .. 0x44].copy_from_slice(b"INFO");
+	b.buf[0x380 .. 0x384].copy_from_slice(b"DATA");
+	let base = 0x48;
+	let (info_at, channels_at) = (0x80, 0xC0);
+	b.offset(base + 0x04, base, info_at);
+	b.offset(base + 0x14, base, channels_at);
+	b.u8(info_at, CODEC_DSP_ADPCM);
+	b.u8(info_at + 1, 1);
+	b.u8(info_at + 2, 2);
+	b.u32(info_at + 4, 48000);
+	b.u32(info_at + 8, 1000);
+	b.u32(info_at + 0xC, 50000);
+	b.u32(info_at + 0x10, 4);
+	b.u32(info_at + 0x14, 0x2000);
+	b.u32(info_at + 0x18, 14336);
+	b.u32(info_at + 0x20, 6992);
+	b.u32(info_at + 0x24, 0xFA0);
+	// (relative to just after the DATA block header)
+	b.offset(info_at + 0x34, 0x388, 0x3A0);
+	b.u32(channels_at, 2);
+	for n in 0 .. 2 {
+	    // these offsets are relative to the table, then the entry
+	    let channel_at = 0x100 + n * 8;
+	    let adpcm_at = 0x200 + n * 0x30;
+	    b.offset(channels_at + 8 + n * 8, channels_at, channel_at);
+	    b.offset(channel_at + 4, channel_at, adpcm_at);
+	    b.adpcm(adpcm_at, n as i16, false);
+	}
+	let (info, dsp_channels) = parse_cstm_fstm(&b.build()).unwrap();
+	assert_eq!((info.codec, info.loop_flag, info.channel_count),
+		   (CODEC_DSP_ADPCM, true, 2));
+	assert_eq!((info.sample_rate, info.loop_start, info.sample_count),
+		   (48000, 1000, 50000));
+	assert_eq!((info.data_offset, info.block_count, info.block_size,
+		    info.block_samples), (0x3A0, 4, 0x2000, 14336));
+	assert_eq!((info.last_block_samples, info.last_block_padded_size),
+		   (6992, 0xFA0));
+	check_dsp_channels(&dsp_channels);
+    }
+
+    #[test]
+    fn missing_blocks() {
+	let mut b = Builder::new(b"CSTM", false);
+	b.u16(0x10, 1);
+	b.u16(0x14, 0x4000);
+	b.u32(0x18, 0x40);
+	assert!(parse_cstm_fstm(&b.build()).is_err());
+	// HEAD isn't where the header says
+	let mut b = Builder::new(b"RSTM", true);
+	b.u32(0x10, 0x40);
+	assert!(parse_rstm(&b.build()).is_err());
+    }
+
+    #[test]
+    fn dsp_nybbles() {
+	// no prediction: every sample is just its nybble times the scale
+	let mut channel = DspChannel { coefs: [0; 16], hist1: 0, hist2: 0 };
+	let mut out = [9.0; 4];
+	// scale 4, then 1, -1, 7, -8
+	channel.decode(&[0x02, 0x1F, 0x78, 0, 0, 0, 0, 0], 4, &mut out, 1);
+	assert_eq!(out.map(|x| x * 32768.0), [4.0, -4.0, 28.0, -32.0]);
+    }
+
+    #[test]
+    fn dsp_prediction() {
+	// coefficient pair 1 is (1.0, 0.0) in fixed point, so each sample
+	// adds onto the last one, starting from the history
+	let mut coefs = [0; 16];
+	coefs[2] = 2048;
+	let mut channel = DspChannel { coefs, hist1: 100, hist2: 0 };
+	// interleaved with another channel, which is left alone
+	let mut out = [9.0; 6];
+	channel.decode(&[0x10, 0x12, 0x30, 0, 0, 0, 0, 0], 3, &mut out, 2);
+	assert_eq!(out.map(|x| x * 32768.0),
+		   [101.0, 9.0 * 32768.0, 103.0, 9.0 * 32768.0, 106.0,
+		    9.0 * 32768.0]);
+	assert_eq!((channel.hist1, channel.hist2), (106, 103));
+    }
+
+    #[test]
+    fn dsp_clamps() {
+	let mut coefs = [0; 16];
+	coefs[0] = 2048;
+	let mut channel = DspChannel { coefs, hist1: 32000, hist2: 0 };
+	let mut out = [0.0; 2];
+	// scale 2048 pushes it past full scale both ways
+	channel.decode(&[0x0B, 0x79, 0, 0, 0, 0, 0, 0], 2, &mut out, 1);
+	assert_eq!(out.map(|x| x * 32768.0), [32767.0, 32767.0 - 14336.0]);
+    }
+}
@@ -0,0 +1,255 @@
+//! Ogg Opus, via libopus.
+
+use std::fs::File;
+
+use anyhow::anyhow;
+use log::trace;
+use ogg::PacketReader;
+use opus::{Channels, Decoder};
+
+use super::{
+    bytes::{read_u16_le, read_u32_le},
+    granule, LoopPoints, Source, Stream,
+};
+
+/// Opus always decodes at 48kHz, whatever rate the original audio had. This
+/// is also the unit of the granule position, and of sample-based loop tags.
+const OPUS_SAMPLE_RATE: u32 = 48000;
+
+/// The longest an Opus packet can be: 120ms at 48kHz.
+const MAX_PACKET_FRAMES: usize = 5760;
+
+struct OpusSource {
+    rdr: PacketReader<File>,
+    stream_serial: u32,
+    decoder: Decoder,
+    channel_count: usize,
+    /// linear gain from the `OpusHead` output gain
+    gain: f32,
+    /// how many more decoded frames to throw away (pre-skip)
+    frames_to_skip: usize,
+    /// how many frames we've decoded, including ones that got skipped
+    frames_decoded: u64,
+}
+
+/// Parses an `OpusTags` packet into a list of key/value pairs.
+fn parse_tags(buf: &[u8]) -> anyhow::Result<Vec<(String, String)>> {
+    let bad_tags = || anyhow!("malformed OpusTags header");
+    if !buf.starts_with(b"OpusTags") { return Err(bad_tags()) }
+    let mut pos = 8;
+    let read_string = |pos: &mut usize| -> anyhow::Result<String> {
+	if buf.len() < *pos + 4 { return Err(bad_tags()) }
+	let len = read_u32_le(buf, *pos) as usize;
+	*pos += 4;
+	if buf.len() - *pos < len { return Err(bad_tags()) }
+	let ret = String::from_utf8_lossy(&buf[*pos .. *pos + len])
+	    .into_owned();
+	*pos += len;
+	Ok(ret)
+    };
+    let vendor = read_string(&mut pos)?;
+    trace!("Vendor: {}", vendor);
+    if buf.len() < pos + 4 { return Err(bad_tags()) }
+    let comment_count = read_u32_le(buf, pos);
+    pos += 4;
+    let mut ret = Vec::new();
+    for _ in 0 .. comment_count {
+	let comment = read_string(&mut pos)?;
+	match comment.split_once('=') {
+	    Some((key, value)) => ret.push((key.to_owned(), value.to_owned())),
+	    None => trace!("ignoring comment without '=': {}", comment),
+	}
+    }
+    Ok(ret)
+}
+
+/// What we need from an `OpusHead` packet.
+struct OpusHead {
+    channel_count: u32,
+    channels: Channels,
+    /// how many frames at the start are decoder warm-up, not audio
+    pre_skip: usize,
+    /// linear gain from the output gain field
+    gain: f32,
+}
+
+fn parse_head(head: &[u8]) -> anyhow::Result<OpusHead> {
+    if head.len() < 19 || !head.starts_with(b"OpusHead") {
+	return Err(anyhow!("malformed OpusHead header"))
+    }
+    if head[8] & 0xF0 != 0 {
+	return Err(anyhow!("unhandled Opus version: {}", head[8]))
+    }
+    let channel_count = head[9] as u32;
+    let pre_skip = read_u16_le(head, 10) as usize;
+    let input_sample_rate = read_u32_le(head, 12);
+    let output_gain = read_u16_le(head, 16) as i16;
+    let mapping_family = head[18];
+    trace!("Opus: {} channels, pre-skip {}, originally {}Hz, gain {}/256dB, \
+	    mapping family {}", channel_count, pre_skip, input_sample_rate,
+	   output_gain, mapping_family);
+    let channels = match (mapping_family, channel_count) {
+	(_, 0) => return Err(anyhow!("stream says it has no channels")),
+	(0, 1) => Channels::Mono,
+	(0, 2) => Channels::Stereo,
+	// family 1 with a single stream and the identity mapping is the same
+	// thing as family 0
+	(1, 1) if head.get(19..22) == Some(&[1, 0, 0]) => Channels::Mono,
+	(1, 2) if head.get(19..23) == Some(&[1, 1, 0, 1]) => Channels::Stereo,
+	(0, x) => return Err(anyhow!("unhandled Opus channel count: {}", x)),
+	(x, _) => return Err(anyhow!("unhandled Opus channel mapping \
+				      family: {}", x)),
+    };
+    Ok(OpusHead {
+	channel_count, channels, pre_skip,
+	gain: 10.0f32.powf(output_gain as f32 / (20.0 * 256.0)),
+    })
+}
+
+pub fn open(file: File) -> anyhow::Result<Stream> {
+    let mut rdr = PacketReader::new(file);
+    let head = rdr.read_packet_expected()?;
+    let stream_serial = head.stream_serial();
+    let OpusHead { channel_count, channels, pre_skip, gain }
+	= parse_head(&head.data)?;
+    let tags = loop {
+	let packet = rdr.read_packet_expected()?;
+	if packet.stream_serial() == stream_serial { break packet.data }
+    };
+    let tags = parse_tags(&tags)?;
+    let (rdr, end) = granule::find_end(rdr, stream_serial)?;
+    let loop_points = LoopPoints::from_comments(&tags, OPUS_SAMPLE_RATE)?;
+    let decoder = Decoder::new(OPUS_SAMPLE_RATE, channels)?;
+    Ok(Stream {
+	sample_rate: OPUS_SAMPLE_RATE, channel_count, loop_points,
+	// (the granule position counts the pre-skip, but we don't)
+	length: end.map(|x| (x as usize).saturating_sub(pre_skip)),
+	source: Box::new(OpusSource {
+	    rdr, stream_serial, decoder,
+	    channel_count: channel_count as usize,
+	    gain,
+	    frames_to_skip: pre_skip,
+	    frames_decoded: 0,
+	}),
+    })
+}
+
+impl Source for OpusSource {
+    fn next_packet(&mut self) -> anyhow::Result<Option<Vec<f32>>> {
+	let packet = loop {
+	    match self.rdr.read_packet()? {
+		Some(x) if x.stream_serial() == self.stream_serial => break x,
+		Some(_) => continue,
+		None => return Ok(None),
+	    }
+	};
+	if packet.data.is_empty() { return Ok(Some(vec![])) }
+	let mut out_buf = vec![0.0; MAX_PACKET_FRAMES * self.channel_count];
+	let mut frame_count = self.decoder.decode_float(&packet.data,
+							&mut out_buf, false)?;
+	if packet.last_in_stream() {
+	    // the granule position of the last page says where the stream
+	    // really ends (counting the pre-skip)
+	    let frames_left = packet.absgp_page()
+		.saturating_sub(self.frames_decoded);
+	    if (frame_count as u64) > frames_left {
+		frame_count = frames_left as usize;
+	    }
+	}
+	self.frames_decoded += frame_count as u64;
+	out_buf.truncate(frame_count * self.channel_count);
+	if self.frames_to_skip > 0 {
+	    let skipped = self.frames_to_skip.min(frame_count);
+	    out_buf.drain(.. skipped * self.channel_count);
+	    self.frames_to_skip -= skipped;
+	}
+	if self.gain != 1.0 {
+	    for x in out_buf.iter_mut() { *x *= self.gain; }
+	}
+	Ok(Some(out_buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(channel_count: u8, pre_skip: u16, gain: i16, mapping: &[u8])
+	    -> Vec<u8> {
+	let mut ret = b"OpusHead\x01".to_vec();
+	ret.push(channel_count);
+	ret.extend_from_slice(&pre_skip.to_le_bytes());
+	ret.extend_from_slice(&44100u32.to_le_bytes());
+	ret.extend_from_slice(&gain.to_le_bytes());
+	ret.extend_from_slice(mapping);
+	ret
+    }
+
+    fn tags(comments: &[&str]) -> Vec<u8> {
+	let mut ret = b"OpusTags".to_vec();
+	ret.extend_from_slice(&12u32.to_le_bytes());
+	ret.extend_from_slice(b"libopus test");
+	ret.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+	for x in comments.iter() {
+	    ret.extend_from_slice(&(x.len() as u32).to_le_bytes());
+	    ret.extend_from_slice(x.as_bytes());
+	}
+	ret
+    }
+
+    #[test]
+    fn stereo_head() {
+	let stereo = parse_head(&head(2, 312, 0, &[0])).unwrap();
+	assert_eq!(stereo.channel_count, 2);
+	assert_eq!(stereo.channels as i32, Channels::Stereo as i32);
+	assert_eq!(stereo.pre_skip, 312);
+	assert_eq!(stereo.gain, 1.0);
+    }
+
+    #[test]
+    fn output_gain() {
+	// Q7.8 decibels: -6dB is about half
+	let quiet = parse_head(&head(1, 0, -6 * 256, &[0])).unwrap();
+	assert!((quiet.gain - 0.501).abs() < 0.001);
+    }
+
+    #[test]
+    fn mapping_families() {
+	// family 1, but really just mono
+	let mono = parse_head(&head(1, 0, 0, &[1, 1, 0, 0])).unwrap();
+	assert_eq!(mono.channels as i32, Channels::Mono as i32);
+	// real surround, which we don't do
+	assert!(parse_head(&head(6, 0, 0, &[1, 4, 2, 0, 4, 1, 2, 3, 5]))
+		.is_err());
+	assert!(parse_head(&head(0, 0, 0, &[0])).is_err());
+	assert!(parse_head(b"OpusHead\x01\x02").is_err());
+    }
+
+    #[test]
+    fn loop_tags() {
+	let comments = parse_tags(&tags(&["TITLE=Theme", "LOOPSTART=96000",
+					  "LOOPLENGTH=480000",
+					  "no equals sign"])).unwrap();
+	assert_eq!(comments.len(), 3);
+	// sample counts are 48kHz ones, counted after the pre-skip (which is
+	// also how we count, since we throw the pre-skip away)
+	let loop_points = LoopPoints::from_comments(&comments,
+						    OPUS_SAMPLE_RATE)
+	    .unwrap();
+	assert_eq!((loop_points.left, loop_points.right), (96000, 576000));
+	// and seconds are seconds, whatever the input sample rate was
+	let comments = parse_tags(&tags(&["LOOP_START=2.5"])).unwrap();
+	let loop_points = LoopPoints::from_comments(&comments,
+						    OPUS_SAMPLE_RATE)
+	    .unwrap();
+	assert_eq!(loop_points.left, 120000);
+    }
+
+    #[test]
+    fn bad_tags() {
+	assert!(parse_tags(b"OpusHead").is_err());
+	let mut truncated = tags(&["LOOPSTART=96000"]);
+	truncated.pop();
+	assert!(parse_tags(&truncated).is_err());
+    }
+}
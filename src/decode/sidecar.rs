@@ -0,0 +1,169 @@
+//! Loop definitions stored next to the audio, for files whose own metadata
+//! we can't (or don't want to) touch.
+//!
+//! The format is a small subset of TOML: one `key = value` per line, with
+//! `#` starting a comment. For example:
+//!
+//! ```toml
+//! start = 12.5
+//! end = 60
+//! unit = "seconds"  # or "samples"
+//! mix = false
+//! crossfade = 0.05
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use log::warn;
+
+use super::{LoopOverrides, Time};
+
+/// Looks for a loop file belonging to `path`: `song.ogg.loop`, then
+/// `song.loop.toml`.
+pub fn find(path: &Path) -> Option<PathBuf> {
+    let mut appended = path.as_os_str().to_owned();
+    appended.push(".loop");
+    [PathBuf::from(appended), path.with_extension("loop.toml")]
+	.into_iter().find(|x| x.is_file())
+}
+
+/// Strips a trailing comment and surrounding whitespace, respecting quotes.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (n, c) in line.char_indices() {
+	match (quote, c) {
+	    (None, '#') => return line[..n].trim(),
+	    (None, '"') | (None, '\'') => quote = Some(c),
+	    (Some(q), c) if q == c => quote = None,
+	    _ => (),
+	}
+    }
+    line.trim()
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+	if let Some(x) = value.strip_prefix(quote)
+	    .and_then(|x| x.strip_suffix(quote)) {
+		return x
+	    }
+    }
+    value
+}
+
+pub fn read(path: &Path) -> anyhow::Result<LoopOverrides> {
+    parse(&std::fs::read_to_string(path)?, path)
+}
+
+/// Parses the contents of a loop file. (`path` is just for error messages.)
+fn parse(text: &str, path: &Path) -> anyhow::Result<LoopOverrides> {
+    let mut start = None;
+    let mut end = None;
+    let mut length = None;
+    let mut samples = false;
+    let mut mix = None;
+    let mut crossfade = None;
+    for (n, line) in text.lines().enumerate() {
+	let line = strip_comment(line);
+	if line.is_empty() { continue }
+	let bad_line = |why: &str| anyhow!("{}:{}: {}", path.display(),
+					   n + 1, why);
+	let (key, value) = match line.split_once('=') {
+	    Some((key, value)) => (key.trim(), unquote(value.trim())),
+	    None => return Err(bad_line("expected `key = value`")),
+	};
+	let number = || value.parse::<f64>().ok().filter(|x| *x >= 0.0)
+	    .ok_or_else(|| bad_line("expected a non-negative number"));
+	match key {
+	    "start" => start = Some(number()?),
+	    "end" => end = Some(number()?),
+	    "length" => length = Some(number()?),
+	    "crossfade" => crossfade = Some(number()?),
+	    "unit" => samples = match value {
+		"seconds" => false,
+		"samples" => true,
+		_ => return Err(bad_line("unit should be \"seconds\" or \
+					  \"samples\"")),
+	    },
+	    "mix" => mix = Some(match value {
+		"true" => true,
+		"false" => false,
+		_ => return Err(bad_line("mix should be true or false")),
+	    }),
+	    _ => warn!("{}:{}: unknown key {:?}", path.display(), n + 1, key),
+	}
+    }
+    let time = |x: f64| if samples { Time::Samples(x as usize) }
+    else { Time::Seconds(x) };
+    Ok(LoopOverrides {
+	start: start.map(time),
+	end: end.map(time),
+	length: length.map(time),
+	mix,
+	crossfade: crossfade.map(time),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> anyhow::Result<LoopOverrides> {
+	super::parse(text, Path::new("song.ogg.loop"))
+    }
+
+    #[test]
+    fn seconds() {
+	let overrides = parse("# a comment\n\
+			       start = 12.5\n\
+			       \n\
+			       end = 60  # trailing comment\n\
+			       unit = \"seconds\"\n\
+			       mix = true\n\
+			       crossfade = 0.05\n").unwrap();
+	assert_eq!(overrides, LoopOverrides {
+	    start: Some(Time::Seconds(12.5)),
+	    end: Some(Time::Seconds(60.0)),
+	    length: None,
+	    mix: Some(true),
+	    crossfade: Some(Time::Seconds(0.05)),
+	});
+    }
+
+    #[test]
+    fn samples() {
+	// the unit applies to every time, even ones before it
+	let overrides = parse("start = 44100\nlength = 441000\n\
+			       unit = 'samples'\n").unwrap();
+	assert_eq!(overrides, LoopOverrides {
+	    start: Some(Time::Samples(44100)),
+	    length: Some(Time::Samples(441000)),
+	    ..Default::default()
+	});
+    }
+
+    #[test]
+    fn quoted_hash() {
+	assert_eq!(strip_comment("unit = \"#seconds\" # no"),
+		   "unit = \"#seconds\"");
+	assert_eq!(strip_comment("  # all comment"), "");
+    }
+
+    #[test]
+    fn unknown_key() {
+	// warned about, not an error
+	assert_eq!(parse("start = 1\nloop_count = 3\n").unwrap().start,
+		   Some(Time::Seconds(1.0)));
+    }
+
+    #[test]
+    fn bad_lines() {
+	let err = parse("start = 1\nend 2\n").unwrap_err();
+	assert_eq!(err.to_string(), "song.ogg.loop:2: expected `key = value`");
+	assert!(parse("start = -1").is_err());
+	assert!(parse("end = soon").is_err());
+	assert!(parse("unit = \"minutes\"").is_err());
+	assert!(parse("mix = yes").is_err());
+    }
+}
@@ -0,0 +1,489 @@
+//! Ogg Vorbis, via Lewton, trimmed at both ends according to the Ogg granule
+//! positions. Damaged pages and packets are replaced with silence, using the
+//! granule positions to work out how much.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{Cursor, ErrorKind, Read, Seek, SeekFrom},
+};
+
+use anyhow::anyhow;
+use lewton::{
+    audio::{PreviousWindowRight, read_audio_packet_generic},
+    header::{
+	IdentHeader, SetupHeader,
+	read_header_comment, read_header_ident, read_header_setup,
+    },
+    inside_ogg::read_headers,
+};
+use log::{trace, warn};
+use ogg::{OggReadError, Packet, PacketReader};
+
+use super::{granule::{self, NO_GRANULE}, LoopPoints, Source, Stream};
+
+/// How far ahead of where we're seeking to we start decoding, in samples.
+/// The first packet after a seek only primes the decoder, and we can't tell
+/// exactly where we are until the end of a page, so this has to cover at
+/// least a page or so. If it doesn't, we try again with twice as much.
+const SEEK_PREROLL: u64 = 16384;
+
+/// Where the pages come from: the file, or a copy of part of it.
+enum Input {
+    File(File),
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+	match self {
+	    Input::File(x) => x.read(buf),
+	    Input::Memory(x) => x.read(buf),
+	}
+    }
+}
+
+impl Seek for Input {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+	match self {
+	    Input::File(x) => x.seek(pos),
+	    Input::Memory(x) => x.seek(pos),
+	}
+    }
+}
+
+struct VorbisSource {
+    rdr: PacketReader<Input>,
+    serial: u32,
+    ident: IdentHeader,
+    setup: SetupHeader,
+    pwr: PreviousWindowRight,
+    channel_order: Vec<usize>,
+    /// the granule position at the end of the last packet we decoded, once
+    /// we know it
+    absgp: Option<u64>,
+    /// the granule position of the first sample we return (nonzero only for
+    /// streams that were cut out of the middle of a longer one)
+    first_absgp: u64,
+    /// where the first page after the headers is in the file
+    audio_start: u64,
+    /// audio that we had to decode early, while looking for a granule
+    /// position
+    pending: VecDeque<Vec<f32>>,
+    /// if true, damage is an error instead of something to recover from
+    strict: bool,
+    /// set when the file turns out to be truncated
+    ended: bool,
+    /// how many bytes of the file we may copy into memory to save us going
+    /// back to it for every seek
+    cache_limit: usize,
+    /// the last frame we seeked to, and where in the file we started
+    /// decoding to get there
+    last_seek: Option<(usize, u64)>,
+}
+
+/// Returns, for each channel we output, which Vorbis channel it comes from.
+///
+/// Vorbis puts the center channel between the front left and right, and the
+/// LFE channel last. Everybody else (WAVE, PortAudio, most sound APIs) wants
+/// front left, front right, center, LFE, then the rear and side channels. For
+/// more than eight channels, the order is application defined, so we pass it
+/// through untouched.
+fn vorbis_channel_order(channel_count: u32) -> Vec<usize> {
+    match channel_count {
+	// L C R → L R C
+	3 => vec![0, 2, 1],
+	// L C R RL RR → L R C RL RR
+	5 => vec![0, 2, 1, 3, 4],
+	// L C R RL RR LFE → L R C LFE RL RR
+	6 => vec![0, 2, 1, 5, 3, 4],
+	// L C R SL SR RC LFE → L R C LFE RC SL SR
+	7 => vec![0, 2, 1, 6, 5, 3, 4],
+	// L C R SL SR RL RR LFE → L R C LFE RL RR SL SR
+	8 => vec![0, 2, 1, 7, 5, 6, 3, 4],
+	x => (0 .. x as usize).collect(),
+    }
+}
+
+pub fn open(file: File, strict: bool) -> anyhow::Result<Stream> {
+    let mut rdr = PacketReader::new(Input::File(file));
+    let ((ident, comment, setup), serial) = read_headers(&mut rdr)?;
+    let channel_count = match ident.audio_channels {
+	0 => return Err(anyhow!("stream says it has no channels")),
+	x => x as u32,
+    };
+    let sample_rate = match ident.audio_sample_rate {
+	0 => return Err(anyhow!("stream says it's 0Hz, that unpossible")),
+	x => x,
+    };
+    trace!("Vendor: {}", comment.vendor);
+    let loop_points = LoopPoints::from_comments(&comment.comment_list,
+						sample_rate)?;
+    let channel_order = vorbis_channel_order(channel_count);
+    let audio_start = rdr.seek_bytes(SeekFrom::Current(0))?;
+    let mut source = VorbisSource {
+	rdr, serial, ident, setup, channel_order,
+	pwr: PreviousWindowRight::new(),
+	absgp: None,
+	first_absgp: 0, audio_start,
+	pending: VecDeque::new(),
+	strict,
+	ended: false,
+	cache_limit: 0,
+	last_seek: None,
+    };
+    source.sync(None)?;
+    let (rdr, end) = granule::find_end(source.rdr, serial)?;
+    source.rdr = rdr;
+    Ok(Stream {
+	sample_rate, channel_count, loop_points,
+	length: end.map(|x| x.saturating_sub(source.first_absgp) as usize),
+	source: Box::new(source),
+    })
+}
+
+impl VorbisSource {
+    /// Returns the next packet of our logical stream, following chained
+    /// streams if they're compatible.
+    fn next_ogg_packet(&mut self) -> anyhow::Result<Option<Packet>> {
+	loop {
+	    if self.ended { return Ok(None) }
+	    let pck = match self.rdr.read_packet()? {
+		Some(x) => x,
+		None => return Ok(None),
+	    };
+	    if pck.stream_serial() == self.serial { return Ok(Some(pck)) }
+	    if !pck.first_in_stream() {
+		// some other stream that's multiplexed with ours
+		continue
+	    }
+	    // a chained stream, which we can only follow if it sounds the
+	    // same as the one we were playing
+	    let ident = match read_header_ident(&pck.data) {
+		Ok(x) => x,
+		// not Vorbis, not our problem
+		Err(_) => continue,
+	    };
+	    if ident.audio_channels != self.ident.audio_channels
+		|| ident.audio_sample_rate != self.ident.audio_sample_rate {
+		    return Err(anyhow!("chained stream has a different channel \
+					count or sample rate"))
+		}
+	    let pck = self.rdr.read_packet_expected()?;
+	    read_header_comment(&pck.data)?;
+	    let pck = self.rdr.read_packet_expected()?;
+	    self.setup = read_header_setup(&pck.data, ident.audio_channels,
+					   (ident.blocksize_0,
+					    ident.blocksize_1))?;
+	    self.ident = ident;
+	    self.serial = pck.stream_serial();
+	    self.pwr = PreviousWindowRight::new();
+	    self.absgp = None;
+	}
+    }
+    /// Decodes the next packet of our stream, truncating it if the granule
+    /// position of the last page says to.
+    fn decode_packet(&mut self) -> anyhow::Result<Option<(Packet,
+							  Vec<Vec<f32>>)>> {
+	let pck = match self.next_ogg_packet()? {
+	    Some(x) => x,
+	    None => return Ok(None),
+	};
+	let mut decoded: Vec<Vec<f32>> = read_audio_packet_generic
+	    (&self.ident, &self.setup, &pck.data, &mut self.pwr)?;
+	let decoded_len = decoded.first().map(Vec::len).unwrap_or(0);
+	if decoded_len > 0 && decoded.len() != self.channel_order.len() {
+	    return Err(anyhow!("packet decoded to {} channels instead of {}",
+			       decoded.len(), self.channel_order.len()))
+	}
+	if decoded.iter().any(|x| x.len() != decoded_len) {
+	    return Err(anyhow!("packet decoded to channels of different \
+				lengths"))
+	}
+	let page_absgp = Some(pck.absgp_page()).filter(|&x| x != NO_GRANULE);
+	if let (Some(absgp), Some(page_absgp), true)
+	    = (self.absgp, page_absgp, pck.last_in_stream()) {
+		// the last page's granule position says where the stream
+		// really ends
+		let wanted = page_absgp.saturating_sub(absgp) as usize;
+		if wanted < decoded_len {
+		    trace!("Trimming {} samples from the end",
+			   decoded_len - wanted);
+		    for channel in decoded.iter_mut() {
+			channel.truncate(wanted);
+		    }
+		}
+	    }
+	if let (Some(page_absgp), true) = (page_absgp, pck.last_in_page()) {
+	    self.absgp = Some(page_absgp);
+	}
+	else if let Some(absgp) = self.absgp.as_mut() {
+	    *absgp += decoded_len as u64;
+	}
+	Ok(Some((pck, decoded)))
+    }
+    /// Decodes up to and including the next page with a granule position,
+    /// and uses that granule position to line the decoded audio up with
+    /// where we were (`expected`, or the beginning of the stream). At the
+    /// beginning, this trims off however much audio the granule position
+    /// says comes before the start. After damage, it fills the gap with
+    /// silence.
+    fn sync(&mut self, mut expected: Option<u64>) -> anyhow::Result<()> {
+	let mut decoded_len = 0;
+	let mut packets = Vec::new();
+	let (page_absgp, is_last) = loop {
+	    match self.decode_packet() {
+		Ok(Some((pck, decoded))) => {
+		    decoded_len += decoded.first().map(Vec::len).unwrap_or(0);
+		    packets.push(decoded);
+		    if pck.last_in_page() && pck.absgp_page() != NO_GRANULE {
+			break (pck.absgp_page(), pck.last_in_stream())
+		    }
+		},
+		Ok(None) => {
+		    self.flush(packets);
+		    return Ok(())
+		},
+		Err(x) => {
+		    // what we decoded before the damage is still good, and
+		    // we know where it goes
+		    let ended = self.recover(x)?;
+		    self.flush(std::mem::take(&mut packets));
+		    expected = Some(expected.unwrap_or(0)
+				    + decoded_len as u64);
+		    decoded_len = 0;
+		    if ended { return Ok(()) }
+		},
+	    }
+	};
+	let start = expected.unwrap_or(0);
+	// (frames of silence to add at the front, frames to skip from the
+	// front, frames to keep after that)
+	let (silence, mut to_skip, mut to_keep) = if is_last {
+	    // the granule position says where the stream ends
+	    let wanted = page_absgp.saturating_sub(start) as usize;
+	    if decoded_len > wanted {
+		trace!("Trimming {} samples from the end",
+		       decoded_len - wanted);
+		(0, 0, wanted)
+	    }
+	    else { (wanted - decoded_len, 0, usize::MAX) }
+	}
+	else {
+	    // the granule position says where the stream is now
+	    let here = start + decoded_len as u64;
+	    if here > page_absgp {
+		trace!("Trimming {} samples from the start", here - page_absgp);
+		(0, (here - page_absgp) as usize, usize::MAX)
+	    }
+	    else {
+		if expected.is_none() {
+		    self.first_absgp = page_absgp - decoded_len as u64;
+		}
+		((page_absgp - here) as usize, 0, usize::MAX)
+	    }
+	};
+	// (a stream that doesn't start at zero doesn't get padded)
+	if silence > 0 && expected.is_some() {
+	    warn!("Replaced {:.3} seconds of damaged audio with silence",
+		  silence as f64 / self.ident.audio_sample_rate as f64);
+	    packets.insert(0, vec![vec![0.0; silence];
+				   self.channel_order.len()]);
+	}
+	for decoded in packets.iter_mut() {
+	    let len = decoded.first().map(Vec::len).unwrap_or(0);
+	    let skipped = to_skip.min(len);
+	    to_skip -= skipped;
+	    let kept = to_keep.min(len - skipped);
+	    to_keep -= kept;
+	    for channel in decoded.iter_mut() {
+		channel.drain(..skipped);
+		channel.truncate(kept);
+	    }
+	}
+	self.flush(packets);
+	Ok(())
+    }
+    /// Seeks so that the next chunk starts exactly at the given sample
+    /// frame. Returns where in the file we started decoding to get there.
+    fn seek_to(&mut self, frame: usize) -> anyhow::Result<u64> {
+	let goal = self.first_absgp + frame as u64;
+	let mut preroll = SEEK_PREROLL;
+	loop {
+	    self.pending.clear();
+	    self.pwr = PreviousWindowRight::new();
+	    self.absgp = None;
+	    self.ended = false;
+	    let seek_to = goal.saturating_sub(preroll);
+	    if seek_to <= self.first_absgp {
+		// close enough to the beginning to just start over
+		self.rdr.seek_bytes(SeekFrom::Start(self.audio_start))?;
+		self.sync(None)?;
+		self.skip(frame)?;
+		return Ok(self.audio_start)
+	    }
+	    if !self.rdr.seek_absgp(Some(self.serial), seek_to)? {
+		return Err(anyhow!("couldn't find granule position {} in the \
+				    stream", seek_to))
+	    }
+	    let start = self.rdr.seek_bytes(SeekFrom::Current(0))?;
+	    // decode up to the end of a page, so we know where we are
+	    let mut decoded_len = 0;
+	    let mut packets = Vec::new();
+	    let page_absgp = loop {
+		let (pck, decoded) = match self.decode_packet()? {
+		    Some(x) => x,
+		    None => return Err(anyhow!("stream ended while seeking")),
+		};
+		decoded_len += decoded.first().map(Vec::len).unwrap_or(0);
+		packets.push(decoded);
+		if pck.last_in_page() && pck.absgp_page() != NO_GRANULE {
+		    break pck.absgp_page()
+		}
+	    };
+	    let here = page_absgp.saturating_sub(decoded_len as u64);
+	    if here > goal {
+		trace!("Seek landed {} samples late, backing up further",
+		       here - goal);
+		preroll *= 2;
+		continue
+	    }
+	    self.flush(packets);
+	    self.skip((goal - here) as usize)?;
+	    return Ok(start)
+	}
+    }
+    /// Swaps the file for a copy of the given part of it, which had better
+    /// be everything that seeking back to the same place will need.
+    fn load_cache(&mut self, start: u64, end: u64) -> anyhow::Result<()> {
+	trace!("Keeping {} bytes of the stream in memory", end - start);
+	let empty = PacketReader::new(Input::Memory(Cursor::new(vec![])));
+	let mut input = std::mem::replace(&mut self.rdr, empty).into_inner();
+	input.seek(SeekFrom::Start(start))?;
+	let mut buf = vec![0; (end - start) as usize];
+	input.read_exact(&mut buf)?;
+	self.rdr = PacketReader::new(Input::Memory(Cursor::new(buf)));
+	// (we only start over from the top if that's where the copy starts)
+	self.audio_start = self.audio_start.saturating_sub(start);
+	Ok(())
+    }
+    /// Throws away the next `frames` sample frames.
+    fn skip(&mut self, frames: usize) -> anyhow::Result<()> {
+	let mut to_skip = frames * self.channel_order.len();
+	while to_skip > 0 {
+	    let mut buf = match self.next_packet()? {
+		Some(x) => x,
+		None => break,
+	    };
+	    if buf.len() <= to_skip { to_skip -= buf.len() }
+	    else {
+		buf.drain(..to_skip);
+		self.pending.push_front(buf);
+		break
+	    }
+	}
+	Ok(())
+    }
+    /// Queues up decoded packets to be returned from `next_packet`.
+    fn flush(&mut self, packets: Vec<Vec<Vec<f32>>>) {
+	for decoded in packets {
+	    let interleaved = self.interleave(decoded);
+	    self.pending.push_back(interleaved);
+	}
+    }
+    /// Gets ready to carry on after damage. Returns true if there's nothing
+    /// left to carry on with.
+    fn recover(&mut self, err: anyhow::Error) -> anyhow::Result<bool> {
+	let at = match self.absgp {
+	    Some(x) => format!("{:.3} seconds", x as f64
+			       / self.ident.audio_sample_rate as f64),
+	    None => "an unknown position".to_owned(),
+	};
+	if self.strict {
+	    return Err(err.context(format!("damaged Vorbis stream at {}", at)))
+	}
+	match err.downcast_ref::<OggReadError>() {
+	    Some(OggReadError::ReadError(x))
+		if x.kind() == ErrorKind::UnexpectedEof => {
+		    warn!("Vorbis stream is truncated at {}", at);
+		    self.ended = true;
+		    return Ok(true)
+		},
+	    Some(OggReadError::ReadError(_)) => return Err(err),
+	    Some(_) => {
+		warn!("Damaged Ogg page at {}, resynchronizing: {}", at, err);
+		// throw away any partial packets, and start with the next
+		// page we can find
+		self.rdr.seek_bytes(SeekFrom::Current(0))?;
+	    },
+	    None => warn!("Damaged Vorbis packet at {}: {}", at, err),
+	}
+	// the next packet has nothing to overlap with
+	self.pwr = PreviousWindowRight::new();
+	self.absgp = None;
+	Ok(false)
+    }
+    /// Interleaves a decoded packet, putting its channels into WAVE order.
+    /// (`decode_packet` has already made sure the channels line up.)
+    fn interleave(&self, pkt: Vec<Vec<f32>>) -> Vec<f32> {
+	if pkt.first().map(Vec::is_empty).unwrap_or(true) { return vec![] }
+	let channel_count = self.channel_order.len();
+	match channel_count {
+	    1 => pkt.into_iter().next().unwrap_or_default(),
+	    _ => {
+		let frame_count = pkt[0].len();
+		let mut out_buf = vec![0.0; frame_count * channel_count];
+		for (n, &channel) in self.channel_order.iter().enumerate() {
+		    for (o, &i) in out_buf[n..].iter_mut()
+			.step_by(channel_count).zip(pkt[channel].iter()) {
+			    *o = i;
+			}
+		}
+		out_buf
+	    },
+	}
+    }
+}
+
+impl Source for VorbisSource {
+    fn next_packet(&mut self) -> anyhow::Result<Option<Vec<f32>>> {
+	if let Some(x) = self.pending.pop_front() { return Ok(Some(x)) }
+	match self.decode_packet() {
+	    Ok(Some((_, decoded))) => Ok(Some(self.interleave(decoded))),
+	    Ok(None) => Ok(None),
+	    Err(x) => {
+		let expected = self.absgp;
+		if !self.recover(x)? {
+		    self.sync(expected)?;
+		}
+		// whatever sync found is in `pending` now
+		Ok(Some(vec![]))
+	    },
+	}
+    }
+    fn can_seek(&self) -> bool { true }
+    fn seek(&mut self, frame: usize) -> anyhow::Result<()> {
+	match self.last_seek {
+	    // back to where we were last time, after reading everything in
+	    // between, which we'll probably want to read again, and again...
+	    Some((last_frame, start)) if last_frame == frame
+		&& self.cache_limit > 0 => {
+		    let end = self.rdr.seek_bytes(SeekFrom::Current(0))?;
+		    if end - start <= self.cache_limit as u64 {
+			self.load_cache(start, end)?;
+		    }
+		    else {
+			trace!("{} bytes is too many to keep in memory",
+			       end - start);
+		    }
+		    // (either way, once is enough)
+		    self.cache_limit = 0;
+		},
+	    _ => (),
+	}
+	let start = self.seek_to(frame)?;
+	self.last_seek = Some((frame, start));
+	Ok(())
+    }
+    fn cache_loops(&mut self, limit: usize) { self.cache_limit = limit }
+}
@@ -0,0 +1,387 @@
+//! RIFF WAVE, with loops from the `smpl` chunk.
+
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+};
+
+use anyhow::anyhow;
+use log::{info, trace, warn};
+
+use super::{bytes::{read_u16_le, read_u32_le}, LoopPoints, Source, Stream};
+
+/// How many frames we read at a time.
+const FRAMES_PER_PACKET: usize = 4096;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+#[derive(Debug,Clone,Copy)]
+enum SampleFormat {
+    U8, I16, I24, I32, F32, F64,
+}
+
+impl SampleFormat {
+    fn bytes(&self) -> usize {
+	match self {
+	    SampleFormat::U8 => 1,
+	    SampleFormat::I16 => 2,
+	    SampleFormat::I24 => 3,
+	    SampleFormat::I32 | SampleFormat::F32 => 4,
+	    SampleFormat::F64 => 8,
+	}
+    }
+    fn decode(&self, x: &[u8]) -> f32 {
+	match self {
+	    SampleFormat::U8 => (x[0] as f32 - 128.0) / 128.0,
+	    SampleFormat::I16 => i16::from_le_bytes([x[0], x[1]]) as f32
+		/ 32768.0,
+	    SampleFormat::I24 => i32::from_le_bytes([0, x[0], x[1], x[2]])
+		as f32 / 2147483648.0,
+	    SampleFormat::I32 => i32::from_le_bytes([x[0], x[1], x[2], x[3]])
+		as f32 / 2147483648.0,
+	    SampleFormat::F32 => f32::from_le_bytes([x[0], x[1], x[2], x[3]]),
+	    SampleFormat::F64 => f64::from_le_bytes([x[0], x[1], x[2], x[3],
+						     x[4], x[5], x[6], x[7]])
+		as f32,
+	}
+    }
+}
+
+struct WavSource {
+    file: BufReader<File>,
+    format: SampleFormat,
+    channel_count: usize,
+    /// how many bytes of the `data` chunk are left to read
+    bytes_left: u64,
+}
+
+/// Text after the fixed fields of an `adtl` subchunk, up to the first NUL.
+fn read_text(buf: &[u8]) -> String {
+    let end = buf.iter().position(|&x| x == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).into_owned()
+}
+
+/// A `cue ` point, with whatever `adtl` had to say about it.
+#[derive(Debug,Default)]
+struct CuePoint {
+    id: u32,
+    position: u32,
+    label: Option<String>,
+    /// from an `ltxt` subchunk, if this cue point starts a region
+    region_length: Option<u32>,
+}
+
+fn parse_fmt(fmt: &[u8]) -> anyhow::Result<(u32, u32, SampleFormat)> {
+    if fmt.len() < 16 { return Err(anyhow!("malformed fmt chunk")) }
+    let mut format_tag = read_u16_le(fmt, 0);
+    let channel_count = read_u16_le(fmt, 2) as u32;
+    let sample_rate = read_u32_le(fmt, 4);
+    let block_align = read_u16_le(fmt, 12) as u32;
+    let bits_per_sample = read_u16_le(fmt, 14);
+    if format_tag == WAVE_FORMAT_EXTENSIBLE {
+	// the real format tag is the start of the subformat GUID
+	if fmt.len() < 26 { return Err(anyhow!("malformed fmt chunk")) }
+	format_tag = read_u16_le(fmt, 24);
+    }
+    let format = match (format_tag, bits_per_sample) {
+	(WAVE_FORMAT_PCM, 8) => SampleFormat::U8,
+	(WAVE_FORMAT_PCM, 16) => SampleFormat::I16,
+	(WAVE_FORMAT_PCM, 24) => SampleFormat::I24,
+	(WAVE_FORMAT_PCM, 32) => SampleFormat::I32,
+	(WAVE_FORMAT_IEEE_FLOAT, 32) => SampleFormat::F32,
+	(WAVE_FORMAT_IEEE_FLOAT, 64) => SampleFormat::F64,
+	(x, y) => return Err(anyhow!("unhandled WAVE format: {:#06X} with {} \
+				      bits per sample", x, y)),
+    };
+    if channel_count == 0 {
+	return Err(anyhow!("stream says it has no channels"))
+    }
+    if sample_rate == 0 {
+	return Err(anyhow!("stream says it's 0Hz, that unpossible"))
+    }
+    if block_align != channel_count * format.bytes() as u32 {
+	return Err(anyhow!("unhandled WAVE block alignment: {}",
+			   block_align))
+    }
+    Ok((channel_count, sample_rate, format))
+}
+
+/// Returns the first forward loop in a `smpl` chunk, as (start, end), with
+/// the end point still inclusive. Logs the others.
+fn parse_smpl(smpl: &[u8]) -> anyhow::Result<Option<(u32, u32)>> {
+    if smpl.len() < 36 { return Err(anyhow!("malformed smpl chunk")) }
+    let loop_count = read_u32_le(smpl, 28) as usize;
+    let mut ret = None;
+    for (n, sample_loop) in smpl[36..].chunks_exact(24).take(loop_count)
+	.enumerate() {
+	    let loop_type = read_u32_le(sample_loop, 4);
+	    let start = read_u32_le(sample_loop, 8);
+	    let end = read_u32_le(sample_loop, 12);
+	    let play_count = read_u32_le(sample_loop, 20);
+	    let type_name = match loop_type {
+		0 => "forward",
+		1 => "alternating",
+		2 => "backward",
+		_ => "unknown",
+	    };
+	    if loop_type == 0 && ret.is_none() {
+		trace!("smpl loop #{}: {} loop from {} to {}, using it", n,
+		       type_name, start, end);
+		ret = Some((start, end));
+	    }
+	    else {
+		warn!("Ignoring smpl loop #{}: {} loop from {} to {} \
+		       (play count {})", n, type_name, start, end,
+		      play_count);
+	    }
+	}
+    Ok(ret)
+}
+
+fn parse_cue(cue: &[u8]) -> anyhow::Result<Vec<CuePoint>> {
+    if cue.len() < 4 { return Err(anyhow!("malformed cue chunk")) }
+    let count = read_u32_le(cue, 0) as usize;
+    Ok(cue[4..].chunks_exact(24).take(count).map(|point| CuePoint {
+	id: read_u32_le(point, 0),
+	// the sample offset, not the "position" field, which is in "play
+	// order" and is usually the same anyway
+	position: read_u32_le(point, 20),
+	..Default::default()
+    }).collect())
+}
+
+fn parse_adtl(adtl: &[u8], cue_points: &mut [CuePoint]) {
+    let mut pos = 0;
+    while adtl.len().saturating_sub(pos) >= 8 {
+	let id = &adtl[pos .. pos + 4];
+	let len = read_u32_le(adtl, pos + 4) as usize;
+	pos += 8;
+	if adtl.len() - pos < len { break }
+	let data = &adtl[pos .. pos + len];
+	pos += len + (len & 1);
+	if data.len() < 4 { continue }
+	let cue_id = read_u32_le(data, 0);
+	let cue_point = match cue_points.iter_mut().find(|x| x.id == cue_id) {
+	    Some(x) => x,
+	    None => continue,
+	};
+	match id {
+	    b"labl" => cue_point.label = Some(read_text(&data[4..])),
+	    b"ltxt" if data.len() >= 8 => {
+		cue_point.region_length = Some(read_u32_le(data, 4));
+		if data.len() > 20 && cue_point.label.is_none() {
+		    cue_point.label = Some(read_text(&data[20..]));
+		}
+	    },
+	    _ => (),
+	}
+    }
+}
+
+pub fn open(mut file: File) -> anyhow::Result<Stream> {
+    let mut header = [0u8; 12];
+    file.read_exact(&mut header)?;
+    if &header[0..4] != b"RIFF" || &header[8..12] != b"WAVE" {
+	return Err(anyhow!("not a RIFF WAVE file"))
+    }
+    let mut fmt = None;
+    let mut data = None;
+    let mut smpl_loop = None;
+    let mut cue_points = Vec::new();
+    let mut adtl = None;
+    loop {
+	let mut chunk_header = [0u8; 8];
+	match file.read_exact(&mut chunk_header) {
+	    Ok(_) => (),
+	    // files that end early are sadly common
+	    Err(x) if x.kind() == std::io::ErrorKind::UnexpectedEof => break,
+	    Err(x) => return Err(x.into()),
+	}
+	let id = &chunk_header[0..4];
+	let len = read_u32_le(&chunk_header, 4) as u64;
+	// chunks are padded to an even length
+	let padded_len = len + (len & 1);
+	trace!("WAVE chunk {:?}, {} bytes", String::from_utf8_lossy(id), len);
+	if id == b"data" {
+	    let offset = file.stream_position()?;
+	    data = Some((offset, len));
+	    file.seek(SeekFrom::Current(padded_len as i64))?;
+	    continue
+	}
+	match id {
+	    b"fmt " | b"smpl" | b"cue " | b"LIST" => (),
+	    _ => {
+		file.seek(SeekFrom::Current(padded_len as i64))?;
+		continue
+	    },
+	}
+	let mut chunk = vec![0u8; len as usize];
+	file.read_exact(&mut chunk)?;
+	if len & 1 != 0 { file.seek(SeekFrom::Current(1))?; }
+	match id {
+	    b"fmt " => fmt = Some(parse_fmt(&chunk)?),
+	    b"smpl" => smpl_loop = parse_smpl(&chunk)?,
+	    b"cue " => cue_points = parse_cue(&chunk)?,
+	    b"LIST" if chunk.starts_with(b"adtl") => adtl = Some(chunk),
+	    _ => (),
+	}
+    }
+    let (channel_count, sample_rate, format) = match fmt {
+	Some(x) => x,
+	None => return Err(anyhow!("WAVE file has no fmt chunk")),
+    };
+    let (data_offset, data_len) = match data {
+	Some(x) => x,
+	None => return Err(anyhow!("WAVE file has no data chunk")),
+    };
+    if let Some(adtl) = adtl {
+	parse_adtl(&adtl[4..], &mut cue_points);
+    }
+    for cue_point in cue_points.iter() {
+	let label = cue_point.label.as_deref().unwrap_or("(unlabeled)");
+	match cue_point.region_length {
+	    Some(length) => info!("Region {:?}: from {} to {}", label,
+				  cue_point.position,
+				  cue_point.position as u64 + length as u64),
+	    None => info!("Marker {:?}: at {}", label, cue_point.position),
+	}
+    }
+    let loop_points = match smpl_loop {
+	// the end point of a smpl loop is the last sample "in" the loop
+	Some((start, end)) if end >= start => LoopPoints {
+	    left: start as usize,
+	    right: end as usize + 1,
+	    mix: false,
+	    crossfade: None,
+	},
+	Some((start, end)) => {
+	    return Err(anyhow!("smpl loop ends ({}) before it starts ({})",
+			       end, start))
+	},
+	None => LoopPoints { left: 0, right: usize::MAX, mix: false,
+			     crossfade: None },
+    };
+    // the data chunk's length can be bogus if the file got truncated
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let data_len = data_len.min(file_len.saturating_sub(data_offset));
+    file.seek(SeekFrom::Start(data_offset))?;
+    let frame_bytes = format.bytes() as u64 * channel_count as u64;
+    Ok(Stream {
+	sample_rate, channel_count, loop_points,
+	length: Some((data_len / frame_bytes) as usize),
+	source: Box::new(WavSource {
+	    file: BufReader::new(file), format,
+	    channel_count: channel_count as usize,
+	    bytes_left: data_len,
+	}),
+    })
+}
+
+impl Source for WavSource {
+    fn next_packet(&mut self) -> anyhow::Result<Option<Vec<f32>>> {
+	let frame_bytes = self.format.bytes() * self.channel_count;
+	let frame_count = (FRAMES_PER_PACKET as u64)
+	    .min(self.bytes_left / frame_bytes as u64) as usize;
+	if frame_count == 0 { return Ok(None) }
+	let mut in_buf = vec![0u8; frame_count * frame_bytes];
+	self.file.read_exact(&mut in_buf)?;
+	self.bytes_left -= in_buf.len() as u64;
+	Ok(Some(in_buf.chunks_exact(self.format.bytes())
+		.map(|x| self.format.decode(x)).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `smpl` chunk with the given loops, as (type, start, end).
+    fn smpl(loops: &[(u32, u32, u32)]) -> Vec<u8> {
+	let mut ret = vec![0u8; 36];
+	ret[28..32].copy_from_slice(&(loops.len() as u32).to_le_bytes());
+	for (n, &(loop_type, start, end)) in loops.iter().enumerate() {
+	    for x in [n as u32, loop_type, start, end, 0, 0] {
+		ret.extend_from_slice(&x.to_le_bytes());
+	    }
+	}
+	ret
+    }
+
+    /// A `cue ` chunk with the given points, as (id, sample offset).
+    fn cue(points: &[(u32, u32)]) -> Vec<u8> {
+	let mut ret = (points.len() as u32).to_le_bytes().to_vec();
+	for &(id, position) in points.iter() {
+	    // the "position" field deliberately disagrees with the offset
+	    for x in [id, 999, u32::from_le_bytes(*b"data"), 0, 0, position] {
+		ret.extend_from_slice(&x.to_le_bytes());
+	    }
+	}
+	ret
+    }
+
+    fn subchunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+	let mut ret = id.to_vec();
+	ret.extend_from_slice(&(data.len() as u32).to_le_bytes());
+	ret.extend_from_slice(data);
+	if data.len() & 1 != 0 { ret.push(0) }
+	ret
+    }
+
+    #[test]
+    fn first_forward_loop() {
+	let chunk = smpl(&[(1, 10, 20), (0, 100, 199), (0, 300, 399)]);
+	assert_eq!(parse_smpl(&chunk).unwrap(), Some((100, 199)));
+    }
+
+    #[test]
+    fn no_forward_loop() {
+	assert_eq!(parse_smpl(&smpl(&[(2, 10, 20)])).unwrap(), None);
+	assert_eq!(parse_smpl(&smpl(&[])).unwrap(), None);
+	assert!(parse_smpl(&[0; 20]).is_err());
+    }
+
+    #[test]
+    fn loop_count_past_end() {
+	// says there are two, but only has room for one
+	let mut chunk = smpl(&[(0, 100, 199)]);
+	chunk[28..32].copy_from_slice(&2u32.to_le_bytes());
+	assert_eq!(parse_smpl(&chunk).unwrap(), Some((100, 199)));
+    }
+
+    #[test]
+    fn cue_points() {
+	let points = parse_cue(&cue(&[(1, 4410), (2, 88200)])).unwrap();
+	assert_eq!(points.iter().map(|x| (x.id, x.position))
+		   .collect::<Vec<_>>(), vec![(1, 4410), (2, 88200)]);
+	assert!(parse_cue(&[1, 0]).is_err());
+    }
+
+    #[test]
+    fn labels_and_regions() {
+	let mut points = parse_cue(&cue(&[(1, 4410), (2, 88200)])).unwrap();
+	let mut adtl = subchunk(b"labl", b"\x01\0\0\0Intro\0");
+	let mut ltxt = vec![2, 0, 0, 0];
+	ltxt.extend_from_slice(&44100u32.to_le_bytes());
+	ltxt.extend_from_slice(b"rgn \0\0\0\0\0\0\0\0Chorus");
+	adtl.extend(subchunk(b"ltxt", &ltxt));
+	// refers to a cue point that doesn't exist
+	adtl.extend(subchunk(b"labl", b"\x09\0\0\0Nowhere\0"));
+	parse_adtl(&adtl, &mut points);
+	assert_eq!(points[0].label.as_deref(), Some("Intro"));
+	assert_eq!(points[0].region_length, None);
+	assert_eq!(points[1].label.as_deref(), Some("Chorus"));
+	assert_eq!(points[1].region_length, Some(44100));
+    }
+
+    #[test]
+    fn unpadded_adtl() {
+	// an odd-length subchunk at the very end, missing its pad byte
+	let mut points = parse_cue(&cue(&[(1, 0)])).unwrap();
+	let mut adtl = subchunk(b"labl", b"\x01\0\0\0Odd\0\0");
+	adtl.pop();
+	parse_adtl(&adtl, &mut points);
+	assert_eq!(points[0].label.as_deref(), Some("Odd"));
+    }
+}
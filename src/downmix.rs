@@ -0,0 +1,160 @@
+//! Folding many channels down to the few that the output device can open.
+//!
+//! Channels are assumed to be in WAVE order (front left, front right, center,
+//! LFE, rear left, rear right, ...), which is what the decoder hands us. The
+//! output device is assumed to use the same order for however many channels
+//! it has.
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Speaker {
+    FrontLeft, FrontRight, Center, Lfe, BackLeft, BackRight, BackCenter,
+    SideLeft, SideRight,
+}
+
+const ATTENUATE_3DB: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Which speaker each channel of a given layout feeds.
+fn layout(channel_count: usize) -> Vec<Speaker> {
+    use Speaker::*;
+    match channel_count {
+	1 => vec![Center],
+	2 => vec![FrontLeft, FrontRight],
+	3 => vec![FrontLeft, FrontRight, Center],
+	4 => vec![FrontLeft, FrontRight, BackLeft, BackRight],
+	5 => vec![FrontLeft, FrontRight, Center, BackLeft, BackRight],
+	6 => vec![FrontLeft, FrontRight, Center, Lfe, BackLeft, BackRight],
+	7 => vec![FrontLeft, FrontRight, Center, Lfe, BackCenter, SideLeft,
+		  SideRight],
+	8 => vec![FrontLeft, FrontRight, Center, Lfe, BackLeft, BackRight,
+		  SideLeft, SideRight],
+	// no defined layout; alternate left and right and hope for the best
+	x => (0 .. x).map(|n| if n % 2 == 0 { FrontLeft } else { FrontRight })
+	    .collect(),
+    }
+}
+
+/// Where a speaker's signal goes, as (speaker, gain) pairs. Each choice is
+/// tried in turn, and the first one whose speakers the output layout has
+/// all of wins. If none do, the channel is dropped.
+fn fold(speaker: Speaker) -> &'static [&'static [(Speaker, f32)]] {
+    use Speaker::*;
+    match speaker {
+	FrontLeft => &[&[(FrontLeft, 1.0)], &[(Center, 0.5)]],
+	FrontRight => &[&[(FrontRight, 1.0)], &[(Center, 0.5)]],
+	Center => &[&[(Center, 1.0)],
+		    &[(FrontLeft, ATTENUATE_3DB), (FrontRight, ATTENUATE_3DB)]],
+	// the usual practice is to drop it on the floor
+	Lfe => &[&[(Lfe, 1.0)]],
+	BackLeft => &[&[(BackLeft, 1.0)], &[(SideLeft, 1.0)],
+		      &[(FrontLeft, 1.0)], &[(Center, 0.5)]],
+	BackRight => &[&[(BackRight, 1.0)], &[(SideRight, 1.0)],
+		       &[(FrontRight, 1.0)], &[(Center, 0.5)]],
+	SideLeft => &[&[(SideLeft, 1.0)], &[(BackLeft, 1.0)],
+		      &[(FrontLeft, 1.0)], &[(Center, 0.5)]],
+	SideRight => &[&[(SideRight, 1.0)], &[(BackRight, 1.0)],
+		       &[(FrontRight, 1.0)], &[(Center, 0.5)]],
+	BackCenter => &[&[(BackCenter, 1.0)],
+			&[(BackLeft, ATTENUATE_3DB),
+			  (BackRight, ATTENUATE_3DB)],
+			&[(SideLeft, ATTENUATE_3DB),
+			  (SideRight, ATTENUATE_3DB)],
+			&[(FrontLeft, 0.5), (FrontRight, 0.5)],
+			&[(Center, 0.5)]],
+    }
+}
+
+pub struct Downmixer {
+    in_channels: usize,
+    out_channels: usize,
+    /// `out_channels` rows of `in_channels` coefficients each
+    matrix: Vec<f32>,
+}
+
+impl Downmixer {
+    /// Makes a downmixer from `in_channels` to `out_channels`, which should
+    /// be fewer.
+    pub fn new(in_channels: u32, out_channels: u32) -> Downmixer {
+	let in_channels = in_channels as usize;
+	let out_channels = out_channels as usize;
+	let out_layout = layout(out_channels);
+	let mut matrix = vec![0.0; in_channels * out_channels];
+	for (n, speaker) in layout(in_channels).into_iter().enumerate() {
+	    let choice = fold(speaker).iter().find(|choice| {
+		choice.iter().all(|(x, _)| out_layout.contains(x))
+	    });
+	    for &(target, gain) in choice.into_iter().flat_map(|x| x.iter()) {
+		// (`choice` only has speakers that are in the layout)
+		let row = out_layout.iter().position(|&x| x == target)
+		    .unwrap();
+		matrix[row * in_channels + n] += gain;
+	    }
+	}
+	// scale so that no output channel can exceed full scale when the
+	// input doesn't
+	let loudest_row = matrix.chunks(in_channels)
+	    .map(|row| row.iter().sum::<f32>())
+	    .fold(0.0, f32::max);
+	if loudest_row > 1.0 {
+	    for x in matrix.iter_mut() { *x /= loudest_row; }
+	}
+	Downmixer { in_channels, out_channels, matrix }
+    }
+    /// Downmixes `input` into `output`, replacing whatever was there.
+    pub fn process(&self, input: &[f32], output: &mut Vec<f32>) {
+	output.clear();
+	output.reserve(input.len() / self.in_channels * self.out_channels);
+	for frame in input.chunks_exact(self.in_channels) {
+	    for row in self.matrix.chunks(self.in_channels) {
+		output.push(frame.iter().zip(row.iter())
+			    .map(|(x, gain)| x * gain).sum());
+	    }
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Downmixes one frame where each channel has a different power of two,
+    /// so it's easy to see which went where.
+    fn mix(in_channels: u32, out_channels: u32) -> Vec<f32> {
+	let downmixer = Downmixer::new(in_channels, out_channels);
+	let frame: Vec<f32> = (0 .. in_channels).map(|n| (1 << n) as f32)
+	    .collect();
+	let mut out = Vec::new();
+	downmixer.process(&frame, &mut out);
+	out
+    }
+
+    #[test]
+    fn five_one_to_stereo() {
+	// L + C*0.707 + BL, over the same for the row sum
+	let scale = 1.0 + ATTENUATE_3DB + 1.0;
+	let out = mix(6, 2);
+	assert!((out[0] - (1.0 + 4.0 * ATTENUATE_3DB + 16.0) / scale).abs()
+		< 1e-4);
+	assert!((out[1] - (2.0 + 4.0 * ATTENUATE_3DB + 32.0) / scale).abs()
+		< 1e-4);
+    }
+
+    #[test]
+    fn five_one_to_quad() {
+	// the back channels stay in the back, and the LFE goes away
+	let scale = 1.0 + ATTENUATE_3DB;
+	let out = mix(6, 4);
+	assert!((out[0] - (1.0 + 4.0 * ATTENUATE_3DB) / scale).abs() < 1e-4);
+	assert!((out[1] - (2.0 + 4.0 * ATTENUATE_3DB) / scale).abs() < 1e-4);
+	assert!((out[2] - 16.0 / scale).abs() < 1e-4);
+	assert!((out[3] - 32.0 / scale).abs() < 1e-4);
+    }
+
+    #[test]
+    fn seven_one_to_five_one() {
+	// the sides fold into the backs, the rest passes through (at half
+	// volume, since the backs could now clip)
+	let out = mix(8, 6);
+	assert_eq!(out, vec![0.5, 1.0, 2.0, 4.0, (16.0 + 64.0) / 2.0,
+			     (32.0 + 128.0) / 2.0]);
+    }
+}
@@ -1,14 +1,19 @@
 use std::{
+    num::NonZeroUsize,
     path::PathBuf,
 };
 
-use clap::Parser;
+use clap::{ArgEnum, Parser};
 
 mod decode;
+mod downmix;
+mod packet;
+mod pipeline;
 mod playback;
+mod renpy;
 mod resample;
 mod terminate;
-use terminate::Terminator;
+use terminate::{OnInterrupt, Terminator};
 mod am_unicode;
 
 #[derive(Parser, Debug)]
@@ -28,15 +33,18 @@ mod am_unicode;
 		     \n\
 		     When you first interrupt this program with control-C, it \
 		     will disengage the loop, bringing the song to its \
-		     natural conclusion. If you interrupt it more times, it \
-		     will make increasingly desperate attempts to exit \
+		     natural conclusion. (Or fade out, or stop, depending on \
+		     --on-interrupt.) If you interrupt it more times, it will \
+		     make increasingly desperate attempts to exit \
 		     immediately.\n\
 		     \n\
 		     Until then, it will play back your audio file, and \
 		     (optionally) display a timeline showing the loop status, \
 		     current time, and where the loop points are.")]
 struct Invocation {
-    /// The path to the Ogg Vorbis file to play.
+    /// The path to the file to play. (Ogg Vorbis, Ogg Opus, FLAC, WAVE,
+    /// AIFF, BRSTM/BCSTM/BFSTM, ADX, or MP3) May be prefixed with Ren'Py
+    /// playback specifiers, e.g. `<from 3 to 60 loop 12.5>theme.ogg`.
     path: PathBuf,
     /// A volume control that multiplies the amplitude. 1.0 = no change, 2.0 =
     /// double amplitude (+6dB), 0.5 = half amplitude (-6dB).
@@ -48,6 +56,76 @@ struct Invocation {
     /// Show the progress bar. (Default if standard error is a terminal.)
     #[clap(short, long)]
     progress: bool,
+    /// Read loop points from this file, overriding the ones in the audio
+    /// file. (Default: `song.ogg.loop` or `song.loop.toml` next to
+    /// `song.ogg`, if either exists.)
+    #[clap(long)]
+    loop_file: Option<PathBuf>,
+    /// Where the loop starts, overriding the file's loop metadata. Given in
+    /// seconds (`12.5`), `mm:ss.fff`, `hh:mm:ss`, or samples (`551250s`).
+    #[clap(long)]
+    loop_start: Option<decode::Time>,
+    /// Where the loop ends, overriding the file's loop metadata. Same
+    /// formats as `--loop-start`.
+    #[clap(long)]
+    loop_end: Option<decode::Time>,
+    /// How long the loop is, overriding the file's loop metadata. Ignored if
+    /// `--loop-end` is also given.
+    #[clap(long)]
+    loop_length: Option<decode::Time>,
+    /// How long to cross-lap the end of the loop into its start, overriding
+    /// the file's `LOOP_CROSSFADE`. Same formats as `--loop-start`.
+    /// (Default: 32 samples.)
+    #[clap(long)]
+    crossfade: Option<decode::Time>,
+    /// The shape of that cross-lap: `linear`, `equal-power`, or
+    /// `raised-cosine`. (Default: `linear`, or `equal-power` for
+    /// `--ambient-fade`.)
+    #[clap(long)]
+    crossfade_curve: Option<decode::CrossfadeCurve>,
+    /// For audio that was never meant to loop: hold back this much of the
+    /// end of the loop, and fade it into the start of the loop every time it
+    /// comes around. Same formats as `--loop-start`.
+    #[clap(long)]
+    ambient_fade: Option<decode::Time>,
+    /// Play the loop this many times, then carry on to the end of the song,
+    /// as if control-C had been pressed once. The first time through counts,
+    /// so `--loops 1` plays the song straight through.
+    #[clap(long)]
+    loops: Option<NonZeroUsize>,
+    /// Play for about this long, then end the song gracefully. Same formats
+    /// as `--loop-start`.
+    #[clap(long, conflicts_with = "loops")]
+    duration: Option<decode::Time>,
+    /// How `--duration` ends the song. `natural` picks the number of loops
+    /// whose natural ending finishes closest to the requested time. `fade`
+    /// fades out, finishing exactly at the requested time.
+    #[clap(long, arg_enum, default_value = "natural")]
+    duration_mode: DurationMode,
+    /// What the first control-C does: `finish` disengages the loop and lets
+    /// the song end naturally, `fade:SECONDS` fades out over that many
+    /// seconds, and `stop` stops right away.
+    #[clap(long, default_value = "finish")]
+    on_interrupt: OnInterrupt,
+    /// Fade in over this long when starting. Same formats as `--loop-start`.
+    /// (Default: a few milliseconds, just enough to avoid a click.)
+    #[clap(long)]
+    fade_in: Option<decode::Time>,
+    /// Stop with an error if the file is damaged or its loop points are past
+    /// its end, instead of papering over the problem.
+    #[clap(long)]
+    strict: bool,
+    /// How many megabytes of decoded audio the loop may take up in memory.
+    /// Longer loops are decoded from the file again every time they come
+    /// around instead. (Ogg Vorbis only.)
+    #[clap(long, default_value_t = 512)]
+    max_loop_memory: usize,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq)]
+enum DurationMode {
+    Natural,
+    Fade,
 }
 
 const NUM_PACKETS_BUFFERED: usize = 30; // thirty? dirty
@@ -65,23 +143,72 @@ fn main() -> anyhow::Result<()> {
 	    std::process::exit(1)
 	},
     };
-    let terminator = Terminator::new();
-    let (sample_rate_in, channel_count, loop_left, loop_right,
-	 decoded_stuff_rx)
-	= decode::start_decoding(&invocation.path, terminator.clone())?;
+    let (path, renpy_prefix) = renpy::parse(&invocation.path)?;
+    let mut decode_options = decode::Options {
+	loop_file: invocation.loop_file,
+	loops: invocation.loops.map(NonZeroUsize::get),
+	strict: invocation.strict,
+	crossfade_curve: invocation.crossfade_curve,
+	ambient_fade: invocation.ambient_fade,
+	max_loop_memory: Some(invocation.max_loop_memory
+			      .saturating_mul(1024 * 1024)),
+	..Default::default()
+    };
+    if let Some(prefix) = renpy_prefix {
+	decode_options.from = prefix.from.map(decode::Time::Seconds);
+	decode_options.to = prefix.to.map(decode::Time::Seconds);
+	decode_options.overrides = prefix.overrides();
+    }
+    // the command line gets the final say
+    let overrides = &mut decode_options.overrides;
+    if invocation.loop_start.is_some() {
+	overrides.start = invocation.loop_start;
+    }
+    if invocation.loop_end.is_some() {
+	overrides.end = invocation.loop_end;
+    }
+    else if invocation.loop_length.is_some() {
+	overrides.end = None;
+	overrides.length = invocation.loop_length;
+    }
+    if invocation.crossfade.is_some() {
+	overrides.crossfade = invocation.crossfade;
+    }
+    let mut fade_out_at = None;
+    match invocation.duration_mode {
+	DurationMode::Natural => decode_options.duration = invocation.duration,
+	DurationMode::Fade => fade_out_at = invocation.duration,
+    }
+    let mut playback_options = playback::Options {
+	volume: invocation.volume,
+	progress,
+	fade_out_at,
+	fade_in: invocation.fade_in,
+	overlap: invocation.ambient_fade,
+	end: None,
+    };
+    let terminator = Terminator::new(invocation.on_interrupt);
+    let (sample_rate_in, channel_count, loop_left, loop_right, end,
+	 decoded_stuff_rx, workers)
+	= decode::start_decoding(&path, decode_options,
+				 terminator.clone())?;
+    playback_options.end = end;
     let time_unit = (sample_rate_in as usize)
 	.saturating_mul(channel_count as usize);
-    let (sample_rate_out, resampled_stuff_tx, is_active)
+    let (sample_rate_out, channel_count_out, resampled_stuff_tx,
+	 used_stuff_rx, is_active)
 	= playback::start_playback(sample_rate_in, channel_count,
 				   time_unit, loop_left, loop_right,
 				   terminator.clone(),
-				   invocation.volume,
-				   progress)?;
-    resample::resample(sample_rate_in, sample_rate_out, channel_count,
-		       decoded_stuff_rx, resampled_stuff_tx,
+				   playback_options)?;
+    resample::resample(sample_rate_in, sample_rate_out,
+		       channel_count, channel_count_out,
+		       decoded_stuff_rx, resampled_stuff_tx, &used_stuff_rx,
 		       terminator)?;
     while is_active() {
+	while used_stuff_rx.try_recv().is_ok() {}
 	std::thread::sleep(std::time::Duration::from_millis(50));
     }
+    workers.join()?;
     Ok(())
 }
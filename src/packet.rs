@@ -0,0 +1,85 @@
+//! The chunks of audio that get passed from thread to thread.
+
+use std::{
+    ops::{Deref, Range},
+    sync::Arc,
+};
+
+/// Some interleaved samples. The buffer underneath may be shared with other
+/// packets, so that sending the same audio over and over (i.e. looping)
+/// doesn't allocate or copy anything.
+///
+/// (It's an `Arc<Vec>` rather than an `Arc<[f32]>` so that a freshly decoded
+/// `Vec` can become a packet without being copied.)
+#[derive(Debug,Clone)]
+pub struct Packet {
+    buf: Arc<Vec<f32>>,
+    range: Range<usize>,
+}
+
+impl Packet {
+    /// A packet that's part of a shared buffer.
+    pub fn share(buf: &Arc<Vec<f32>>, range: Range<usize>) -> Packet {
+	assert!(range.start <= range.end && range.end <= buf.len());
+	Packet { buf: buf.clone(), range }
+    }
+    /// Shortens the packet to its first `len` samples.
+    pub fn truncate(&mut self, len: usize) {
+	self.range.end = self.range.end.min(self.range.start + len);
+    }
+    /// Gets the buffer back, if no other packet is sharing it.
+    pub fn into_vec(self) -> Option<Vec<f32>> {
+	Arc::try_unwrap(self.buf).ok()
+    }
+}
+
+impl From<Vec<f32>> for Packet {
+    fn from(vec: Vec<f32>) -> Packet {
+	let range = 0 .. vec.len();
+	Packet { buf: Arc::new(vec), range }
+    }
+}
+
+impl Deref for Packet {
+    type Target = [f32];
+    fn deref(&self) -> &[f32] {
+	&self.buf[self.range.clone()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared() {
+	let buf = Arc::new(vec![1.0, 2.0, 3.0, 4.0, 5.0]);
+	let mut packet = Packet::share(&buf, 1 .. 4);
+	assert_eq!(&packet[..], &[2.0, 3.0, 4.0]);
+	packet.truncate(2);
+	assert_eq!(&packet[..], &[2.0, 3.0]);
+	// can't make it longer
+	packet.truncate(10);
+	assert_eq!(&packet[..], &[2.0, 3.0]);
+	let other = packet.clone();
+	assert_eq!(packet.into_vec(), None);
+	drop(buf);
+	// the last one holding the buffer gets all of it back
+	assert_eq!(other.into_vec(), Some(vec![1.0, 2.0, 3.0, 4.0, 5.0]));
+    }
+
+    #[test]
+    fn from_vec() {
+	let packet = Packet::from(vec![1.0, 2.0]);
+	assert_eq!(&packet[..], &[1.0, 2.0]);
+	assert_eq!(packet.into_vec(), Some(vec![1.0, 2.0]));
+	let empty = Packet::share(&Arc::new(vec![1.0]), 1 .. 1);
+	assert!(empty.is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn past_end() {
+	Packet::share(&Arc::new(vec![1.0, 2.0]), 1 .. 3);
+    }
+}
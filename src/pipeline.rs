@@ -0,0 +1,68 @@
+//! What can go wrong in the threads that decode, loop, and resample the
+//! audio, and how it gets back to `main`.
+
+use std::{
+    fmt::{Display, Formatter},
+    thread::JoinHandle,
+};
+
+#[derive(Debug)]
+pub enum PipelineError {
+    /// The loop ends before (or where) it starts. (In sample frames.)
+    LoopEndBeforeStart { start: usize, end: usize },
+    /// A loop point is past the end of the stream. (`point` is which one,
+    /// and the rest are in sample frames.)
+    LoopPastEnd { point: &'static str, at: usize, length: usize },
+    /// The decoder gave up.
+    Decode(anyhow::Error),
+    /// The resampler gave up.
+    Resample(anyhow::Error),
+    /// The loop thread lost track of the loop.
+    Loop(anyhow::Error),
+    /// A thread panicked. (Its name is attached.)
+    Panicked(String),
+}
+
+impl Display for PipelineError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+	match self {
+	    PipelineError::LoopEndBeforeStart { start, end } =>
+		write!(f, "LOOP_END precedes LOOP_START (the loop starts at \
+			   sample {} and ends at sample {})", start, end),
+	    PipelineError::LoopPastEnd { point, at, length } =>
+		write!(f, "{} (sample {}) is past the end of the stream \
+			   (sample {})", point, at, length),
+	    PipelineError::Decode(x) =>
+		write!(f, "error while decoding stream: {:#}", x),
+	    PipelineError::Resample(x) =>
+		write!(f, "error while resampling: {:#}", x),
+	    PipelineError::Loop(x) =>
+		write!(f, "error while looping: {:#}", x),
+	    PipelineError::Panicked(x) => write!(f, "the {} panicked", x),
+	}
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+/// The threads that are feeding audio to the playback callback.
+pub struct Workers {
+    handles: Vec<JoinHandle<Result<(), PipelineError>>>,
+}
+
+impl Workers {
+    pub fn new(handles: Vec<JoinHandle<Result<(), PipelineError>>>)
+	       -> Workers {
+	Workers { handles }
+    }
+    /// Waits for every thread to finish, and returns the first error any of
+    /// them had.
+    pub fn join(self) -> Result<(), PipelineError> {
+	for handle in self.handles {
+	    let name = handle.thread().name().unwrap_or("worker thread")
+		.to_owned();
+	    handle.join().map_err(|_| PipelineError::Panicked(name))??;
+	}
+	Ok(())
+    }
+}
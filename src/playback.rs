@@ -1,7 +1,7 @@
 use std::sync::{
     Arc,
     atomic::{AtomicUsize, Ordering},
-    mpsc::{SyncSender, sync_channel, TryRecvError},
+    mpsc::{Receiver, SyncSender, sync_channel, TryRecvError},
 };
 
 use anyhow::anyhow;
@@ -12,31 +12,91 @@ use portaudio::{
     StreamCallbackResult,
 };
 
-use crate::Terminator;
+use crate::{
+    Terminator,
+    decode::Time,
+    packet::Packet,
+};
+
+/// How long a fade out lasts when the user asks us to fade out at a certain
+/// time, but not how quickly.
+const DEFAULT_FADE_SECONDS: f64 = 10.0;
+
+/// How long the ramps are that keep us from clicking when we start or stop
+/// mid-waveform.
+const DECLICK_SECONDS: f64 = 0.01;
+
+/// A fade out, in output frames since playback began.
+struct FadeOut {
+    start: usize,
+    end: usize,
+}
+
+impl FadeOut {
+    /// The gain to apply at a given frame.
+    fn gain(&self, frame: usize) -> f32 {
+	if frame < self.start { 1.0 }
+	else if frame >= self.end { 0.0 }
+	else { (self.end - frame) as f32 / (self.end - self.start) as f32 }
+    }
+}
+
+/// Applies the volume, the fade in, and any fade outs to `buffer`, whose
+/// first frame is `first_frame` frames after playback began.
+fn apply_gain(buffer: &mut [f32], channel_count: usize, first_frame: usize,
+	      volume: f32, fade_in_start: Option<usize>, fade_in_len: usize,
+	      fade_outs: &[FadeOut]) {
+    // (packets may be shared with other packets, so the volume gets applied
+    // here rather than to them)
+    if volume != 1.0 {
+	for x in buffer.iter_mut() { *x *= volume }
+    }
+    let faded_in = fade_in_start
+	.is_some_and(|x| first_frame >= x + fade_in_len);
+    if faded_in && fade_outs.is_empty() { return }
+    for (n, frame) in buffer.chunks_mut(channel_count).enumerate() {
+	let t = first_frame + n;
+	let fade_in_gain = match fade_in_start {
+	    // (nothing but silence so far)
+	    None => 0.0,
+	    Some(start) if t >= start + fade_in_len => 1.0,
+	    Some(start) => (t - start + 1) as f32 / fade_in_len as f32,
+	};
+	// if fades overlap, the quietest one wins
+	let gain = fade_outs.iter()
+	    .map(|x| x.gain(t))
+	    .fold(fade_in_gain, f32::min);
+	for x in frame.iter_mut() { *x *= gain }
+    }
+}
 
-fn print_progress(cur: usize, loop_left: usize, loop_right: &Arc<AtomicUsize>,
-		  time_unit: usize, terminator: &Terminator, unicode: bool)
+fn print_progress(cur: usize, loop_left: usize, loop_right: usize,
+		  end: Option<usize>, overlap: usize, terminator: &Terminator,
+		  unicode: bool)
 {
     struct Theme {
 	line: char, open: char, closed_left: char, closed_right: char,
-	time_left: char, time_right: char, 
+	time_left: char, time_right: char, overlap: char,
     }
     let theme = if unicode {
 	Theme { line: '─', open: '⋯', closed_left: '╟', closed_right: '╢',
-		time_left: '┤', time_right: '├' }
+		time_left: '┤', time_right: '├', overlap: '═' }
     }
     else {
 	Theme { line: '-', open: '+', closed_left: '[', closed_right: ']',
-		time_left: '<', time_right: '>' }
+		time_left: '<', time_right: '>', overlap: '=' }
     };
     let cols = terminal_size::terminal_size().map(|(w,_)| w.0).unwrap_or(80)
 	as usize;
-    let loop_right = loop_right.load(Ordering::Relaxed) / time_unit;
     // we... don't expect that this display will be... useful... for a multi-
     // hour recording.
     let left_pos = format!("  {}:{:02} ", loop_left / 60, loop_left % 60);
-    let right_pos = if loop_right == 0 { " ?:??".to_owned() }
+    let mut right_pos = if loop_right == 0 { " ?:??".to_owned() }
     else { format!(" {}:{:02}", loop_right / 60, loop_right % 60) };
+    // if there's an outro after the loop, show where it ends too
+    if let Some(end) = end.filter(|&x| x > loop_right) {
+	right_pos.push_str(&format!(" / {}:{:02}", end / 60, end % 60));
+    }
     let cur_pos = format!("{}:{:02}", cur / 60, cur % 60);
     let mut bar = left_pos;
     bar.reserve(cols*2); //heh
@@ -53,12 +113,21 @@ fn print_progress(cur: usize, loop_left: usize, loop_right: &Arc<AtomicUsize>,
 	let right_bracket = if !terminator.should_loop() { theme.open }
 	else if loop_right == 0 { '?' }
 	else { theme.closed_right };
+	// the part at the start of the loop where an ambient fade overlaps
+	let overlap_cols = if loop_right == 0 { 0 }
+	else {
+	    let loop_len = (loop_right - loop_left).max(1);
+	    // (the loop thread won't let it take more than half the loop)
+	    overlap.min(loop_len / 2) * rem_cols / loop_len
+	};
+	let line = |col| if col < overlap_cols { theme.overlap }
+	else { theme.line };
 	bar.push(left_bracket);
-	for _ in 0 .. fill_amt { bar.push(theme.line); }
+	for col in 0 .. fill_amt { bar.push(line(col)); }
 	bar.push(theme.time_left);
 	bar.push_str(&cur_pos);
 	bar.push(theme.time_right);
-	for _ in fill_amt .. rem_cols { bar.push(theme.line); }
+	for col in fill_amt .. rem_cols { bar.push(line(col)); }
 	bar.push(right_bracket);
 	bar.push_str(&right_pos);
 	eprint!("\r{}\r", bar);
@@ -78,20 +147,54 @@ fn end_progress() {
     }
 }
 
+/// How the user wants playback to go.
+#[derive(Debug)]
+pub struct Options {
+    /// What to multiply every sample by.
+    pub volume: f32,
+    /// Whether to show the progress bar.
+    pub progress: bool,
+    /// When to finish fading out, if ever.
+    pub fade_out_at: Option<Time>,
+    /// How long to take fading in, if longer than the usual de-click ramp.
+    pub fade_in: Option<Time>,
+    /// How much of the start of the loop an ambient fade overlaps, if any.
+    pub overlap: Option<Time>,
+    /// Where the stream ends (in floats, like loop positions), if we know.
+    pub end: Option<usize>,
+}
+
 pub fn start_playback(sample_rate: u32, channel_count: u32,
 		      time_unit: usize, loop_left: usize,
 		      loop_right: Arc<AtomicUsize>,
 		      terminator: Terminator,
-		      volume: f32, progress: bool) -> anyhow::Result<(u32,SyncSender<(usize, Vec<f32>)>, Box<dyn Fn() -> bool>)> {
+		      options: Options) -> anyhow::Result<(u32,u32,SyncSender<(usize, Packet)>, Receiver<Packet>, Box<dyn Fn() -> bool>)> {
+    let Options { volume, progress, fade_out_at, fade_in, overlap,
+		  end } = options;
     let unicode = crate::am_unicode::am_unicode();
+    // (in seconds, like the rest of the progress bar)
+    let overlap = overlap
+	.map(|x| x.to_frames(sample_rate).div_ceil(sample_rate as usize))
+	.unwrap_or(0);
     let loop_left = loop_left / time_unit;
+    let end = end.map(|x| x / time_unit);
     let pa = PortAudio::new().expect("initializing portaudio");
     let output_device = pa.default_output_device().unwrap();
+    let device_info = pa.device_info(output_device)?;
+    let channel_count = match device_info.max_output_channels {
+	x if x >= channel_count as i32 => channel_count,
+	x if x >= 1 => {
+	    info!("output device only has {} channels, downmixing {} \
+		   channels to fit", x, channel_count);
+	    x as u32
+	},
+	_ => return Err(anyhow!("output device has no output channels")),
+    };
     let parameters = Parameters::new(output_device, channel_count as i32,
 				     true, // interleaved
 				     1.0);
     let flags = portaudio::stream_flags::Flags::empty();
-    let sample_rate = match pa.device_info(output_device)?.default_sample_rate{
+    let sample_rate = match device_info.default_sample_rate {
 	x if x < 1.0 || x >= 1048576.0 => {
 	    info!("no default sample rate, using input rate of {}",
 		  sample_rate);
@@ -101,22 +204,65 @@ pub fn start_playback(sample_rate: u32, channel_count: u32,
     };
     let settings = OutputSettings::with_flags(parameters, sample_rate as f64,
 					      0, flags);
-    let (tx, rx) = sync_channel::<(usize, Vec<f32>)>(crate::NUM_PACKETS_BUFFERED);
+    let (tx, rx) = sync_channel::<(usize, Packet)>(crate::NUM_PACKETS_BUFFERED);
+    // packets we're done with go back the other way, since freeing memory
+    // isn't something the audio thread should be doing. (there's room for
+    // everything that could be in flight, so sending never fails while
+    // anyone's listening.)
+    let (used_tx, used_rx)
+	= sync_channel::<Packet>(crate::NUM_PACKETS_BUFFERED * 2 + 1);
     let mut leftovers: Vec<f32> = Vec::with_capacity(32768); // sure!
     let mut last_pos = None;
+    // how many frames we've handed to PortAudio so far
+    let mut frames_played = 0;
+    let mut fade_outs: Vec<FadeOut> = fade_out_at.into_iter().map(|x| {
+	let end = x.to_frames(sample_rate);
+	let len = Time::Seconds(DEFAULT_FADE_SECONDS).to_frames(sample_rate);
+	FadeOut { start: end.saturating_sub(len), end }
+    }).collect();
+    let mut interrupt_faded = false;
+    let declick_len = Time::Seconds(DECLICK_SECONDS).to_frames(sample_rate);
+    let mut declicking = false;
+    let fade_in_len = fade_in.map(|x| x.to_frames(sample_rate)).unwrap_or(0)
+	.max(declick_len);
+    // the frame where real audio started, once it has. (the pipeline takes
+    // a moment to get going, and fading in over silence would be a waste.)
+    let mut fade_in_start = None;
     let callback = move |args: OutputCallbackArgs<f32>| {
 	let OutputCallbackArgs {
 	    buffer,
 	    ..
 	} = args;
+	if let (false, Some(seconds)) = (interrupt_faded,
+					 terminator.should_fade()) {
+	    interrupt_faded = true;
+	    let len = Time::Seconds(seconds).to_frames(sample_rate);
+	    fade_outs.push(FadeOut { start: frames_played,
+				     end: frames_played + len });
+	}
+	if fade_outs.iter().any(|x| frames_played >= x.end) {
+	    // faded all the way out, we're done
+	    terminator.terminate();
+	}
+	let buffer_len = buffer.len();
 	let mut rem = &mut buffer[..];
 	if terminator.should_terminate() {
-	    rem.fill(0.0);
-	    while let Ok(_) = rx.try_recv() {}
-	    if progress {
-		end_progress();
+	    if fade_outs.iter().any(|x| frames_played >= x.end) {
+		rem.fill(0.0);
+		while let Ok((_, x)) = rx.try_recv() {
+		    let _ = used_tx.try_send(x);
+		}
+		if progress {
+		    end_progress();
+		}
+		return StreamCallbackResult::Complete
+	    }
+	    if !declicking {
+		// stopping mid-waveform would click, so ramp down first
+		declicking = true;
+		fade_outs.push(FadeOut { start: frames_played,
+					 end: frames_played + declick_len });
 	    }
-	    return StreamCallbackResult::Complete
 	}
 	if leftovers.len() > 0 {
 	    if rem.len() >= leftovers.len() {
@@ -134,22 +280,35 @@ pub fn start_playback(sample_rate: u32, channel_count: u32,
 	let mut cur_pos = None;
 	while rem.len() > 0 {
 	    assert!(leftovers.len() == 0);
-	    let (pos, mut next_packet) = match rx.try_recv() {
+	    let (pos, next_packet) = match rx.try_recv() {
 		Ok(x) => x,
 		Err(TryRecvError::Empty) => break,
 		Err(TryRecvError::Disconnected) => {
+		    let filled = buffer_len - rem.len();
 		    rem.fill(0.0);
+		    if fade_in_start.is_none() && filled > 0 {
+			fade_in_start = Some(frames_played);
+		    }
+		    if declicking {
+			// the pipeline stopped before the ramp could finish.
+			// squeeze what's left of it into what we have, or it
+			// ends in a click after all
+			let filled_frames = filled / channel_count as usize;
+			fade_outs.push(FadeOut {
+			    start: frames_played,
+			    end: frames_played + filled_frames,
+			});
+		    }
+		    // the last of the audio still needs its volume and fades
+		    apply_gain(&mut buffer[..filled], channel_count as usize,
+			       frames_played, volume, fade_in_start,
+			       fade_in_len, &fade_outs);
 		    if progress {
 			end_progress();
 		    }
 		    return StreamCallbackResult::Complete
 		},
 	    };
-	    if volume != 1.0 {
-		for x in next_packet.iter_mut() {
-		    *x *= volume;
-		}
-	    }
 	    if next_packet.len() <= rem.len() {
 		(&mut rem[..next_packet.len()]).copy_from_slice(&next_packet);
 		rem = &mut rem[next_packet.len()..];
@@ -159,19 +318,31 @@ pub fn start_playback(sample_rate: u32, channel_count: u32,
 		leftovers.extend_from_slice(&next_packet[rem.len()..]);
 		rem = &mut [];
 	    }
+	    let _ = used_tx.try_send(next_packet);
 	    cur_pos = Some(pos);
 	}
+	if fade_in_start.is_none() && rem.len() < buffer_len {
+	    fade_in_start = Some(frames_played);
+	}
 	if rem.len() > 0 {
 	    rem.fill(0.0);
-	    warn!("playback buffer underrun!");
+	    // (the pipeline is allowed to run dry while we're stopping)
+	    if !declicking {
+		warn!("playback buffer underrun!");
+	    }
 	}
+	apply_gain(buffer, channel_count as usize, frames_played, volume,
+		   fade_in_start, fade_in_len, &fade_outs);
+	frames_played += buffer.len() / channel_count as usize;
 	if let Some(cur_pos) = cur_pos {
 	    let cur_pos = cur_pos / time_unit;
 	    if Some(cur_pos) != last_pos {
 		last_pos = Some(cur_pos);
 		if progress {
-		    print_progress(cur_pos, loop_left, &loop_right, time_unit,
-				   &terminator, unicode);
+		    print_progress(cur_pos, loop_left,
+				   loop_right.load(Ordering::Relaxed)
+				   / time_unit,
+				   end, overlap, &terminator, unicode);
 		}
 	    }
 	}
@@ -182,5 +353,31 @@ pub fn start_playback(sample_rate: u32, channel_count: u32,
     stream.start()
 	.or_else(|x| Err(anyhow!("Unable to start audio stream: {}", x)))?;
     let is_active = move || stream.is_active().ok().unwrap_or(false);
-    Ok((sample_rate, tx, Box::new(is_active)))
+    Ok((sample_rate, channel_count, tx, used_rx, Box::new(is_active)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volume_and_fades() {
+	let mut buffer = vec![1.0; 8];
+	// stereo, four frames in, near the end of a four-frame fade in, and
+	// halfway through a fade out that ends two frames from now
+	apply_gain(&mut buffer, 2, 4, 0.5, Some(2), 4, &[FadeOut {
+	    start: 2, end: 6,
+	}]);
+	assert_eq!(buffer, vec![0.25, 0.25, 0.125, 0.125, 0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn silence_before_fade_in() {
+	let mut buffer = vec![1.0; 4];
+	apply_gain(&mut buffer, 1, 0, 1.0, None, 4, &[]);
+	assert_eq!(buffer, vec![0.0; 4]);
+	let mut buffer = vec![1.0; 4];
+	apply_gain(&mut buffer, 1, 100, 1.0, Some(0), 4, &[]);
+	assert_eq!(buffer, vec![1.0; 4]);
+    }
 }
@@ -0,0 +1,110 @@
+//! Ren'Py's "partial playback" syntax, where a file name can be prefixed
+//! with a few playback specifiers in angle brackets:
+//!
+//! ```text
+//! <from 3 to 60 loop 12.5>music/theme.ogg
+//! ```
+//!
+//! `from` is where playback starts, `to` is where it ends, and `loop` is
+//! where it goes back to when it loops. All are in seconds. If `loop` isn't
+//! given, playback loops back to `from` (or the beginning) instead.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+
+use crate::decode::{LoopOverrides, Time};
+
+#[derive(Debug,Default,PartialEq)]
+pub struct Prefix {
+    pub from: Option<f64>,
+    pub to: Option<f64>,
+    pub loop_start: Option<f64>,
+}
+
+impl Prefix {
+    /// The loop Ren'Py would play. Ren'Py doesn't look at loop metadata, so
+    /// this overrides all of it.
+    pub fn overrides(&self) -> LoopOverrides {
+	LoopOverrides {
+	    start: Some(Time::Seconds(self.loop_start.or(self.from)
+				      .unwrap_or(0.0))),
+	    end: Some(match self.to {
+		Some(x) => Time::Seconds(x),
+		// the end of the file, wherever that is
+		None => Time::Samples(usize::MAX),
+	    }),
+	    length: None,
+	    mix: Some(false),
+	    crossfade: None,
+	}
+    }
+}
+
+/// Splits a Ren'Py prefix off of `path`, if it has one.
+pub fn parse(path: &Path) -> anyhow::Result<(PathBuf, Option<Prefix>)> {
+    let rest = match path.to_str().and_then(|x| x.strip_prefix('<')) {
+	Some(x) => x,
+	None => return Ok((path.to_owned(), None)),
+    };
+    let (spec, path) = match rest.split_once('>') {
+	Some(x) => x,
+	None => return Err(anyhow!("Ren'Py-style path has a '<' but no '>'")),
+    };
+    let mut prefix = Prefix::default();
+    let mut words = spec.split_whitespace();
+    while let Some(word) = words.next() {
+	let slot = match word {
+	    "from" => &mut prefix.from,
+	    "to" => &mut prefix.to,
+	    "loop" => &mut prefix.loop_start,
+	    x => return Err(anyhow!("unsupported Ren'Py playback specifier: \
+				     {:?}", x)),
+	};
+	let value = words.next()
+	    .and_then(|x| x.parse::<f64>().ok())
+	    .filter(|x| *x >= 0.0)
+	    .ok_or_else(|| anyhow!("Ren'Py {:?} needs a number of seconds \
+				    after it", word))?;
+	*slot = Some(value);
+    }
+    Ok((PathBuf::from(path), Some(prefix)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_prefix() {
+	let (path, prefix) = parse(Path::new("music/theme.ogg")).unwrap();
+	assert_eq!(path, Path::new("music/theme.ogg"));
+	assert_eq!(prefix, None);
+    }
+
+    #[test]
+    fn all_specifiers() {
+	let (path, prefix) = parse(Path::new("<from 3 to 60 loop 12.5>\
+					      music/theme.ogg")).unwrap();
+	assert_eq!(path, Path::new("music/theme.ogg"));
+	assert_eq!(prefix, Some(Prefix { from: Some(3.0), to: Some(60.0),
+					 loop_start: Some(12.5) }));
+    }
+
+    #[test]
+    fn loop_defaults_to_from() {
+	let (_, prefix) = parse(Path::new("<from 3>theme.ogg")).unwrap();
+	let overrides = prefix.unwrap().overrides();
+	assert_eq!(overrides.start, Some(Time::Seconds(3.0)));
+	assert_eq!(overrides.end, Some(Time::Samples(usize::MAX)));
+	assert_eq!(overrides.mix, Some(false));
+    }
+
+    #[test]
+    fn bad_prefixes() {
+	assert!(parse(Path::new("<from 3 theme.ogg")).is_err());
+	assert!(parse(Path::new("<from>theme.ogg")).is_err());
+	assert!(parse(Path::new("<from -3>theme.ogg")).is_err());
+	assert!(parse(Path::new("<silence 3>theme.ogg")).is_err());
+    }
+}
@@ -2,17 +2,51 @@ use std::sync::mpsc::{SyncSender, Receiver};
 
 use libsoxr::Soxr;
 
-use crate::Terminator;
+use anyhow::anyhow;
 
-pub fn resample(sample_rate_in: u32, sample_rate_out: u32, channel_count: u32,
-		in_rx: Receiver<(usize, Vec<f32>)>,
-		out_tx: SyncSender<(usize, Vec<f32>)>,
+use crate::{
+    Terminator,
+    downmix::Downmixer,
+    packet::Packet,
+    pipeline::PipelineError,
+};
+
+/// Takes back the packets that playback is done with, so that they get freed
+/// here instead of on the audio thread, and keeps some of their buffers to
+/// fill again.
+fn reclaim(used_rx: &Receiver<Packet>, spares: &mut Vec<Vec<f32>>) {
+    while let Ok(x) = used_rx.try_recv() {
+	if spares.len() >= crate::NUM_PACKETS_BUFFERED { continue }
+	if let Some(x) = x.into_vec() { spares.push(x) }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn resample(sample_rate_in: u32, sample_rate_out: u32,
+		channel_count_in: u32, channel_count: u32,
+		in_rx: Receiver<(usize, Packet)>,
+		out_tx: SyncSender<(usize, Packet)>,
+		used_rx: &Receiver<Packet>,
 		terminator: Terminator)
 		-> anyhow::Result<()> {
+    // downmix before resampling, so that soxr has fewer channels to chew on
+    let downmixer = if channel_count_in != channel_count {
+	Some(Downmixer::new(channel_count_in, channel_count))
+    } else { None };
+    let mut spares = Vec::new();
     if sample_rate_in == sample_rate_out {
 	// Easy!
-	while let Ok((pos, x)) = in_rx.recv() {
+	for (pos, x) in in_rx {
 	    if terminator.should_terminate() { break }
+	    reclaim(used_rx, &mut spares);
+	    let x = match downmixer.as_ref() {
+		Some(downmixer) => {
+		    let mut out_buf = spares.pop().unwrap_or_default();
+		    downmixer.process(&x, &mut out_buf);
+		    out_buf.into()
+		},
+		None => x,
+	    };
 	    out_tx.send((pos, x))?;
 	}
     }
@@ -20,24 +54,47 @@ pub fn resample(sample_rate_in: u32, sample_rate_out: u32, channel_count: u32,
 	let soxr = Soxr::create(sample_rate_in as f64, sample_rate_out as f64,
 				channel_count, None, None, None)?;
 	let mut last_pos = 0;
-	while let Ok((pos, in_buf)) = in_rx.recv() {
+	let mut downmixed = Vec::new();
+	for (pos, packet) in in_rx {
 	    if terminator.should_terminate() { break }
-            assert!(in_buf.len() > 0);
+	    reclaim(used_rx, &mut spares);
+	    let in_buf = match downmixer.as_ref() {
+		Some(downmixer) => {
+		    downmixer.process(&packet, &mut downmixed);
+		    &downmixed[..]
+		},
+		None => &packet[..],
+	    };
+	    if in_buf.is_empty() {
+		return Err(PipelineError::Resample(anyhow!("got an empty \
+							    packet")).into())
+	    }
 	    let capacity = in_buf.len()
 		.checked_mul(sample_rate_out as usize)
 		.and_then(|x| x.checked_add(sample_rate_out as usize - 1))
 		.expect("arithmetic overflow caught, buffer overrun averted")
 		/ (sample_rate_in as usize);
-	    let mut out_buf = vec![0.0f32; capacity];
+	    let mut out_buf = spares.pop().unwrap_or_default();
+	    out_buf.clear();
+	    out_buf.resize(capacity, 0.0);
 	    let (processed_in, processed_out)
-		= soxr.process(Some(&in_buf), &mut out_buf[..])?;
-	    assert_eq!(processed_in, in_buf.len() / channel_count as usize);
+		= soxr.process(Some(in_buf), &mut out_buf[..])?;
+	    if processed_in != in_buf.len() / channel_count as usize {
+		return Err(PipelineError::Resample(anyhow!(
+		    "soxr only took {} of {} frames", processed_in,
+		    in_buf.len() / channel_count as usize)).into())
+	    }
 	    let processed_out_floats
 		= processed_out.checked_mul(channel_count as usize)
 		.expect("arithmetic overflow caught, buffer overrun averted");
-	    assert!(out_buf.len() >= processed_out_floats);
+	    if processed_out_floats > out_buf.len() {
+		return Err(PipelineError::Resample(anyhow!(
+		    "soxr claims to have output {} frames into room for {}",
+		    processed_out, out_buf.len() / channel_count as usize))
+			   .into())
+	    }
 	    out_buf.resize(processed_out_floats, 0.0);
-	    out_tx.send((pos, out_buf))?;
+	    out_tx.send((pos, out_buf.into()))?;
 	    last_pos = pos;
 	}
 	let mut out_buf = vec![0.0f32; 1024];
@@ -46,12 +103,14 @@ pub fn resample(sample_rate_in: u32, sample_rate_out: u32, channel_count: u32,
 	let processed_out_floats
 	    = processed_out.checked_mul(channel_count as usize)
 	    .expect("arithmetic overflow caught, buffer overrun averted");
-	assert!(out_buf.len() >= processed_out_floats);
-	out_buf.resize(processed_out.checked_mul(channel_count as usize)
-		       .expect("arithmetic overflow caught, buffer \
-				overrun averted"),
-		       0.0);
-	out_tx.send((last_pos, out_buf))?;
+	if processed_out_floats > out_buf.len() {
+	    return Err(PipelineError::Resample(anyhow!(
+		"soxr claims to have flushed {} frames into room for {}",
+		processed_out, out_buf.len() / channel_count as usize))
+		       .into())
+	}
+	out_buf.resize(processed_out_floats, 0.0);
+	out_tx.send((last_pos, out_buf.into()))?;
     }
     Ok(())
 }
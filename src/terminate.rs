@@ -1,19 +1,50 @@
 use std::{
     sync::{
 	Arc,
-	atomic::{AtomicU32, Ordering},
+	atomic::{AtomicBool, AtomicU32, Ordering},
     }
 };
 
+/// What the first control-C does.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum OnInterrupt {
+    /// Disengage the loop, and let the song come to its natural conclusion.
+    Finish,
+    /// Fade out over this many seconds.
+    Fade(f64),
+    /// Stop right away.
+    Stop,
+}
+
+impl std::str::FromStr for OnInterrupt {
+    type Err = String;
+    fn from_str(s: &str) -> Result<OnInterrupt, String> {
+	match s {
+	    "finish" => Ok(OnInterrupt::Finish),
+	    "stop" => Ok(OnInterrupt::Stop),
+	    _ => match s.strip_prefix("fade:").map(str::parse::<f64>) {
+		Some(Ok(x)) if x.is_finite() && x >= 0.0 =>
+		    Ok(OnInterrupt::Fade(x)),
+		_ => Err(format!("{:?} should be \"finish\", \"stop\", or \
+				  \"fade:\" followed by a number of seconds",
+				 s)),
+	    },
+	}
+    }
+}
+
 #[derive(Debug,Clone)]
 pub struct Terminator {
     ctrlc_count: Arc<AtomicU32>,
+    /// Set when something other than control-C disengaged the loop.
+    loop_stopped: Arc<AtomicBool>,
+    on_interrupt: OnInterrupt,
 }
 
 impl Terminator {
-    pub fn new() -> Terminator {
-	let ctrlc_count = Arc::new(AtomicU32::new(0));
-	let ctrlc_count_clone = ctrlc_count.clone();
+    pub fn new(on_interrupt: OnInterrupt) -> Terminator {
+	let ret = Terminator::unhooked(on_interrupt);
+	let ctrlc_count_clone = ret.ctrlc_count.clone();
 	ctrlc::set_handler(move || {
 	    let n = ctrlc_count_clone.load(Ordering::Relaxed);
 	    let n = n + 1;
@@ -28,15 +59,121 @@ impl Terminator {
 	    };
 	    ctrlc_count_clone.store(n, Ordering::Relaxed);
 	}).expect("unable to set control-C handler");
-	Terminator { ctrlc_count }
+	ret
+    }
+    /// Makes a terminator without hooking it up to control-C, which can
+    /// only be done once.
+    fn unhooked(on_interrupt: OnInterrupt) -> Terminator {
+	Terminator { ctrlc_count: Arc::new(AtomicU32::new(0)), on_interrupt,
+		     loop_stopped: Arc::new(AtomicBool::new(false)) }
     }
     fn fetch(&self) -> u32 {
 	self.ctrlc_count.load(Ordering::Relaxed)
     }
+    /// Disengages the loop, just like the first control-C would by default.
+    pub fn stop_looping(&self) {
+	self.loop_stopped.store(true, Ordering::Relaxed);
+    }
+    /// Stops playback right away, just like the second control-C would.
+    pub fn terminate(&self) {
+	self.ctrlc_count.fetch_max(2, Ordering::Relaxed);
+    }
     pub fn should_loop(&self) -> bool {
-	self.fetch() == 0
+	if self.loop_stopped.load(Ordering::Relaxed) { return false }
+	match (self.fetch(), self.on_interrupt) {
+	    (0, _) => true,
+	    // keep looping while we fade out
+	    (1, OnInterrupt::Fade(_)) => true,
+	    _ => false,
+	}
     }
     pub fn should_terminate(&self) -> bool {
-	self.fetch() > 1
+	match (self.fetch(), self.on_interrupt) {
+	    (1, OnInterrupt::Stop) => true,
+	    // if the loop was already disengaged, the first control-C has
+	    // nothing left to finish
+	    (1, OnInterrupt::Finish)
+		=> self.loop_stopped.load(Ordering::Relaxed),
+	    (x, _) => x > 1,
+	}
+    }
+    /// If control-C has asked us to fade out, returns how many seconds the
+    /// fade should take.
+    pub fn should_fade(&self) -> Option<f64> {
+	match (self.fetch(), self.on_interrupt) {
+	    (1, OnInterrupt::Fade(x)) => Some(x),
+	    _ => None,
+	}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Terminator {
+	fn interrupt(&self) {
+	    self.ctrlc_count.fetch_add(1, Ordering::Relaxed);
+	}
+	fn state(&self) -> (bool, bool, Option<f64>) {
+	    (self.should_loop(), self.should_terminate(), self.should_fade())
+	}
+    }
+
+    #[test]
+    fn parse() {
+	assert_eq!("finish".parse(), Ok(OnInterrupt::Finish));
+	assert_eq!("stop".parse(), Ok(OnInterrupt::Stop));
+	assert_eq!("fade:2.5".parse(), Ok(OnInterrupt::Fade(2.5)));
+	assert_eq!("fade:0".parse(), Ok(OnInterrupt::Fade(0.0)));
+	for bad in ["", "Stop", "fade", "fade:", "fade:-1", "fade:inf",
+		    "fade:NaN", "fade:2s"] {
+	    assert!(bad.parse::<OnInterrupt>().is_err(), "{:?}", bad);
+	}
+    }
+
+    #[test]
+    fn finish() {
+	let terminator = Terminator::unhooked(OnInterrupt::Finish);
+	assert_eq!(terminator.state(), (true, false, None));
+	terminator.interrupt();
+	assert_eq!(terminator.state(), (false, false, None));
+	terminator.interrupt();
+	assert_eq!(terminator.state(), (false, true, None));
+    }
+
+    #[test]
+    fn finish_after_loop_stopped() {
+	let terminator = Terminator::unhooked(OnInterrupt::Finish);
+	terminator.stop_looping();
+	assert_eq!(terminator.state(), (false, false, None));
+	// nothing left to finish, so one control-C is enough
+	terminator.interrupt();
+	assert_eq!(terminator.state(), (false, true, None));
+    }
+
+    #[test]
+    fn fade() {
+	let terminator = Terminator::unhooked(OnInterrupt::Fade(3.0));
+	assert_eq!(terminator.state(), (true, false, None));
+	terminator.interrupt();
+	assert_eq!(terminator.state(), (true, false, Some(3.0)));
+	terminator.interrupt();
+	assert_eq!(terminator.state(), (false, true, None));
+	// stopping the loop some other way doesn't stop the fade
+	let terminator = Terminator::unhooked(OnInterrupt::Fade(3.0));
+	terminator.stop_looping();
+	terminator.interrupt();
+	assert_eq!(terminator.state(), (false, false, Some(3.0)));
+    }
+
+    #[test]
+    fn stop() {
+	let terminator = Terminator::unhooked(OnInterrupt::Stop);
+	terminator.interrupt();
+	assert_eq!(terminator.state(), (false, true, None));
+	let terminator = Terminator::unhooked(OnInterrupt::Fade(3.0));
+	terminator.terminate();
+	assert_eq!(terminator.state(), (false, true, None));
     }
 }